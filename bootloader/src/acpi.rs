@@ -0,0 +1,211 @@
+//! Routines for locating ACPI tables
+
+use core::convert::TryInto;
+
+/// Read a `T` from the physical address `paddr`
+unsafe fn read_phys<T>(paddr: usize) -> T {
+    core::ptr::read_unaligned(paddr as *const T)
+}
+
+/// Compute the 8-bit sum of `len` bytes at the physical address `paddr`. Valid
+/// ACPI structures sum to zero.
+unsafe fn checksum(paddr: usize, len: usize) -> u8 {
+    (0..len).fold(0u8, |acc, ii| acc.wrapping_add(read_phys::<u8>(paddr + ii)))
+}
+
+/// Find the physical address of the RSDP by scanning the first KiB of the
+/// EBDA and the BIOS read-only area
+fn find_rsdp() -> Option<usize> {
+    // Get the EBDA base from the BIOS data area
+    let ebda = unsafe { read_phys::<u16>(0x40e) as usize } << 4;
+
+    for &(start, end) in &[(ebda, ebda + 1024), (0xe0000, 0x100000)] {
+        // The RSDP is always on a 16-byte boundary
+        for paddr in (start..end).step_by(16) {
+            unsafe {
+                if read_phys::<[u8; 8]>(paddr) == *b"RSD PTR " &&
+                        checksum(paddr, 20) == 0 {
+                    return Some(paddr);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Find the ACPI table with `signature`, returning the physical address and
+/// length of the table
+pub fn find_table(signature: &[u8; 4]) -> Option<(usize, usize)> {
+    let rsdp = find_rsdp()?;
+
+    unsafe {
+        // Use the XSDT if this is ACPI 2.0 or newer, otherwise the RSDT
+        let xsdt = read_phys::<u64>(rsdp + 24);
+        let (sdt, entry_size) = if read_phys::<u8>(rsdp + 15) >= 2 &&
+                xsdt != 0 {
+            (xsdt.try_into().ok()?, 8)
+        } else {
+            (read_phys::<u32>(rsdp + 16) as usize, 4)
+        };
+
+        // Get the number of tables
+        let sdt_len = read_phys::<u32>(sdt + 4) as usize;
+        let entries = sdt_len.checked_sub(36)? / entry_size;
+
+        for entry in (0..entries).map(|ii| sdt + 36 + ii * entry_size) {
+            // Get the address of the table, skipping tables we can't address
+            let table: usize = if entry_size == 8 {
+                match read_phys::<u64>(entry).try_into() {
+                    Ok(table) => table,
+                    Err(_)    => continue,
+                }
+            } else {
+                read_phys::<u32>(entry) as usize
+            };
+
+            // Check if this is the table we want, and it is valid
+            if read_phys::<[u8; 4]>(table) == *signature {
+                let len = read_phys::<u32>(table + 4) as usize;
+                if checksum(table, len) == 0 {
+                    return Some((table, len));
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Count the enabled processors in the ACPI MADT (signature `APIC`), from
+/// both its local APIC and local x2APIC entries
+pub fn count_cpus() -> Option<u32> {
+    let (madt, len) = find_table(b"APIC")?;
+
+    let mut cpus = 0u32;
+    unsafe {
+        // The variable length entries start after the local APIC address and
+        // flags
+        let mut entry = madt + 44;
+        while entry + 2 <= madt + len {
+            let typ       = read_phys::<u8>(entry);
+            let entry_len = read_phys::<u8>(entry + 1) as usize;
+            if entry_len < 2 || entry + entry_len > madt + len {
+                break;
+            }
+
+            // Get the flags of processor entries, bit 0 is set if the
+            // processor is enabled
+            let flags = match typ {
+                0 if entry_len >= 8  => Some(read_phys::<u32>(entry + 4)),
+                9 if entry_len >= 16 => Some(read_phys::<u32>(entry + 8)),
+                _                    => None,
+            };
+            if flags.map_or(false, |flags| (flags & 1) != 0) {
+                cpus += 1;
+            }
+
+            entry += entry_len;
+        }
+    }
+
+    Some(cpus)
+}
+
+/// An ACPI generic address structure, describing a register
+#[cfg(feature = "crash-reboot")]
+#[derive(Clone, Copy, Debug)]
+pub struct AcpiGenericAddress {
+    /// Address space of the register, 0 is memory and 1 is I/O ports
+    pub address_space: u8,
+
+    /// Size of the register in bits
+    pub bit_width: u8,
+
+    /// Bit offset of the register at `address`
+    pub bit_offset: u8,
+
+    /// Access size of the register
+    pub access_size: u8,
+
+    /// Address of the register in `address_space`
+    pub address: u64,
+}
+
+/// Reset information from the ACPI FADT
+#[cfg(feature = "crash-reboot")]
+#[derive(Clone, Copy, Debug)]
+pub struct FadtInfo {
+    /// Register which resets the system when `reset_value` is written to it,
+    /// if the platform supports it
+    pub reset_reg: Option<AcpiGenericAddress>,
+
+    /// Value to write to `reset_reg` to reset the system
+    pub reset_value: u8,
+}
+
+#[cfg(feature = "crash-reboot")]
+impl FadtInfo {
+    /// Reset the system through the ACPI reset register. Returns if there is
+    /// no reset register or it is in an address space we do not support.
+    pub fn reset(&self) {
+        let reg = match self.reset_reg {
+            Some(reg) => reg,
+            None      => return,
+        };
+
+        // The reset register must be a single byte at bit offset 0, accessed
+        // with byte or undefined (legacy) access size
+        if reg.bit_width != 8 || reg.bit_offset != 0 || reg.access_size > 1 {
+            return;
+        }
+
+        unsafe {
+            match reg.address_space {
+                // System memory, which is identity mapped
+                0 if reg.address < 0x1_0000_0000 => {
+                    cpu::mmio_write8(reg.address as usize as *mut u8,
+                                     self.reset_value);
+                }
+
+                // System I/O
+                1 if reg.address < 0x1_0000 => {
+                    cpu::out8(reg.address as u16, self.reset_value);
+                }
+
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parse the ACPI FADT (signature `FACP`) for the reset register, which the
+/// bootloader uses to reboot
+#[cfg(feature = "crash-reboot")]
+pub fn parse_fadt() -> Option<FadtInfo> {
+    let (fadt, len) = find_table(b"FACP")?;
+
+    unsafe {
+        // The reset register is only valid as of ACPI 2.0, and if the
+        // `RESET_REG_SUP` flag is set
+        let reset_reg = if len >= 129 &&
+                (read_phys::<u32>(fadt + 112) & (1 << 10)) != 0 {
+            Some(AcpiGenericAddress {
+                address_space: read_phys::<u8>(fadt + 116),
+                bit_width:     read_phys::<u8>(fadt + 117),
+                bit_offset:    read_phys::<u8>(fadt + 118),
+                access_size:   read_phys::<u8>(fadt + 119),
+                address:       read_phys::<u64>(fadt + 120),
+            })
+        } else {
+            None
+        };
+        let reset_value =
+            if len >= 129 { read_phys::<u8>(fadt + 128) } else { 0 };
+
+        Some(FadtInfo {
+            reset_reg:   reset_reg,
+            reset_value: reset_value,
+        })
+    }
+}
@@ -0,0 +1,118 @@
+//! Local APIC timer routines, used for timeouts in the bootloader
+//!
+//! The bootloader does not have an IDT, and spends most of its time waiting on
+//! the BIOS in real mode, so the timer interrupt is left masked and the
+//! countdown is polled instead. This means the timer can only bound loops of
+//! non-blocking calls, such as UDP reads, it cannot interrupt a BIOS or PXE
+//! call which never returns.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lockcell::LockCell;
+
+/// `IA32_APIC_BASE` MSR, holding the physical address of the local APIC
+const IA32_APIC_BASE: u32 = 0x1b;
+
+/// Spurious interrupt vector register, holding the APIC software enable
+const APIC_SVR: usize = 0xf0;
+
+/// LVT timer register
+const APIC_LVT_TIMER: usize = 0x320;
+
+/// Timer initial count register
+const APIC_TIMER_INITIAL: usize = 0x380;
+
+/// Timer current count register
+const APIC_TIMER_CURRENT: usize = 0x390;
+
+/// Timer divide configuration register
+const APIC_TIMER_DIVIDE: usize = 0x3e0;
+
+/// Vector which the timer is programmed with. The interrupt is always masked,
+/// this is only so the LVT is never programmed with a reserved vector.
+const TIMER_VECTOR: u32 = 0xfe;
+
+/// The calibrated frequency of the APIC timer in Hz, with a divide by 16,
+/// if it has been calibrated yet
+static TIMER_HZ: LockCell<Option<u64>> = LockCell::new(None);
+
+/// Set once the countdown started by `start_one_shot_timer` has been seen to
+/// expire, such that it stays expired even if the timer is reprogrammed
+static TIMER_EXPIRED: AtomicBool = AtomicBool::new(false);
+
+/// Get the address of the local APIC register `reg`
+unsafe fn reg_ptr(reg: usize) -> *mut u32 {
+    // The APIC is always below 4 GiB, which is identity mapped
+    let base = cpu::rdmsr(IA32_APIC_BASE) & 0xffff_f000;
+    (base as usize + reg) as *mut u32
+}
+
+/// Read the local APIC register `reg`
+unsafe fn read(reg: usize) -> u32 {
+    cpu::mmio_read32(reg_ptr(reg))
+}
+
+/// Write `val` to the local APIC register `reg`
+unsafe fn write(reg: usize, val: u32) {
+    cpu::mmio_write32(reg_ptr(reg), val);
+}
+
+/// Software enable the local APIC and set up the timer as a masked one-shot
+/// countdown with a divide by 16
+unsafe fn init_timer() {
+    write(APIC_SVR, read(APIC_SVR) | (1 << 8));
+    write(APIC_TIMER_DIVIDE, 0b0011);
+    write(APIC_LVT_TIMER, (1 << 16) | TIMER_VECTOR);
+}
+
+/// Get the frequency of the APIC timer in Hz, calibrating it against the PIT
+/// if it has not been calibrated yet
+fn timer_hz() -> u64 {
+    let mut hz = TIMER_HZ.lock();
+
+    *hz.get_or_insert_with(|| unsafe {
+        init_timer();
+
+        // Count down from the maximum for 10 milliseconds
+        write(APIC_TIMER_INITIAL, !0);
+        crate::time::pit_delay_ms(10);
+        let elapsed = !0 - read(APIC_TIMER_CURRENT);
+        write(APIC_TIMER_INITIAL, 0);
+
+        core::cmp::max(elapsed as u64 * 100, 1)
+    })
+}
+
+/// Start a one-shot countdown on the APIC timer which expires after
+/// `microseconds`, replacing any countdown which was already running
+pub fn start_one_shot_timer(microseconds: u32) {
+    let ticks = core::cmp::min(
+        microseconds as u64 * timer_hz() / 1_000_000, !0u32 as u64);
+
+    TIMER_EXPIRED.store(false, Ordering::SeqCst);
+
+    unsafe {
+        init_timer();
+
+        // Writing the initial count starts the countdown, make sure it is
+        // never zero as that stops the timer
+        write(APIC_TIMER_INITIAL, core::cmp::max(ticks as u32, 1));
+    }
+}
+
+/// Stop the countdown started by `start_one_shot_timer`
+pub fn stop_timer() {
+    unsafe { write(APIC_TIMER_INITIAL, 0); }
+}
+
+/// Check if the countdown started by `start_one_shot_timer` has expired
+pub fn timer_expired() -> bool {
+    let expired = TIMER_EXPIRED.load(Ordering::SeqCst) ||
+        unsafe { read(APIC_TIMER_CURRENT) == 0 };
+
+    if expired {
+        TIMER_EXPIRED.store(true, Ordering::SeqCst);
+    }
+
+    expired
+}
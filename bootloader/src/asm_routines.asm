@@ -251,6 +251,24 @@ backout:
 	popad
 	ret
 
+; Marker for the end of the bootloader code. The linker places grouped
+; sections (with a `$` suffix) after all plain `.text` contributions, thus this
+; lands after all code in the image.
+section .text$z
+global ___bootloader_text_end
+___bootloader_text_end:
+
+; Markers for the end of the bootloader data, placed the same way after all
+; plain `.data` and `.bss` contributions. Only discardable sections, such as
+; DWARF debug info, may follow these in the image.
+section .data$z data
+global ___bootloader_data_end
+___bootloader_data_end:
+
+section .bss$z bss
+global ___bootloader_bss_end
+___bootloader_bss_end:
+
 section .data
 
 ; ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
@@ -340,12 +358,64 @@ _enter64:
 	or  eax,  (1 << 31) ; Paging enable
 	mov cr0, eax
 
+	; Make sure long mode is actually active before running 64-bit code, as
+	; a broken environment here would otherwise silently triple fault
+	mov ecx, 0xc0000080
+	rdmsr
+	and eax, (1 << 8) | (1 << 10) ; LME | LMA
+	cmp eax, (1 << 8) | (1 << 10)
+	jne .fail_efer
+
+	mov eax, cr0
+	and eax, (1 << 0) | (1 << 16) | (1 << 31) ; PE | WP | PG
+	cmp eax, (1 << 0) | (1 << 16) | (1 << 31)
+	jne .fail_cr0
+
 	; Load the 64-bit long mode GDT
 	lgdt [lmgdt]
 
 	; Long jump to enable long mode!
 	jmp 0x0008:lm_entry
 
+.fail_efer:
+	mov esi, enter64_efer_msg
+	jmp .fail
+
+.fail_cr0:
+	mov esi, enter64_cr0_msg
+
+.fail:
+	; Our paging state is not what we set, there is nothing sane we can do
+	; other than report which check failed. This is written directly to COM1
+	; with port I/O, as nothing else can be trusted at this point. The message
+	; is right after this code, which must be mapped as we're running it.
+	cld
+.print:
+	lodsb
+	test al, al
+	jz   .halt
+	mov  ah, al
+
+	; Wait for the transmit buffer to be empty
+	mov dx, 0x3f8 + 5
+.wait:
+	in   al, dx
+	test al, 0x20
+	jz   .wait
+
+	mov al, ah
+	mov dx, 0x3f8
+	out dx, al
+	jmp .print
+
+.halt:
+	cli
+	hlt
+	jmp .halt
+
+enter64_efer_msg: db "enter64: EFER.LME or EFER.LMA not set", 13, 10, 0
+enter64_cr0_msg:  db "enter64: CR0.PE, CR0.WP, or CR0.PG not set", 13, 10, 0
+
 [bits 64]
 
 lm_entry:
@@ -0,0 +1,74 @@
+#!/usr/bin/env python3
+"""
+Generate `embedded_test_kernel.bin`, the minimal PE64 which the bootloader
+serves as `chocolate_milk.kern` with the `embedded-test-kernel` feature.
+
+The image has a `.text` section containing a `cli; hlt` loop at the entry
+point, and a 4 KiB `.bss` section. The output is written next to this script.
+"""
+
+import os
+import struct
+
+# Image base and alignments of the image
+IMAGE_BASE        = 0x1337_0000_0000
+SECTION_ALIGNMENT = 0x1000
+FILE_ALIGNMENT    = 0x200
+
+# Offset of the PE header, directly after the DOS header and a DOS stub
+PE = 0x60
+
+# Offset of the section headers, after the COFF and optional headers
+SECTIONS = PE + 0x18 + 0xf0
+
+# cli; hlt; jmp $-1
+TEXT = bytes([0xfa, 0xf4, 0xeb, 0xfd])
+
+def main():
+    pe = bytearray(0x400)
+
+    def put(off, fmt, *vals):
+        struct.pack_into("<" + fmt, pe, off, *vals)
+
+    # DOS header
+    pe[0:2] = b"MZ"
+    put(0x3c, "I", PE)
+
+    # COFF header, x86_64 with two sections, a full PE32+ optional header,
+    # and an executable large address aware image
+    pe[PE:PE + 4] = b"PE\0\0"
+    put(PE + 0x04, "H", 0x8664)
+    put(PE + 0x06, "H", 2)
+    put(PE + 0x14, "H", 0xf0)
+    put(PE + 0x16, "H", 0x22)
+
+    # Optional header
+    put(PE + 0x18, "H", 0x20b)
+    put(PE + 0x28, "I", 0x1000)
+    put(PE + 0x30, "Q", IMAGE_BASE)
+    put(PE + 0x38, "I", SECTION_ALIGNMENT)
+    put(PE + 0x3c, "I", FILE_ALIGNMENT)
+    put(PE + 0x50, "I", 0x3000)
+    put(PE + 0x54, "I", 0x200)
+    put(PE + 0x84, "I", 16)
+
+    # `.text`, RX code, with its raw data at 0x200
+    pe[SECTIONS:SECTIONS + 8] = b".text\0\0\0"
+    put(SECTIONS + 0x08, "IIII", len(TEXT), 0x1000, 0x200, 0x200)
+    put(SECTIONS + 0x24, "I", 0x6000_0020)
+
+    # `.bss`, RW uninitialized data with no raw data
+    pe[SECTIONS + 0x28:SECTIONS + 0x30] = b".bss\0\0\0\0"
+    put(SECTIONS + 0x30, "II", 0x1000, 0x2000)
+    put(SECTIONS + 0x4c, "I", 0xc000_0080)
+
+    # Code
+    pe[0x200:0x200 + len(TEXT)] = TEXT
+
+    out = os.path.join(os.path.dirname(os.path.abspath(__file__)),
+                       "embedded_test_kernel.bin")
+    with open(out, "wb") as fd:
+        fd.write(pe)
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,66 @@
+//! Structured boot event log, emitted as one JSON object per line over serial
+//! such that automated tooling can parse it without matching on free-form
+//! log messages
+
+use core::fmt::Write;
+use crate::print::SerialWriter;
+
+/// A value of a field in a boot event
+#[derive(Clone, Copy)]
+pub enum JsonValue<'a> {
+    /// A string, escaped on output
+    Str(&'a str),
+
+    /// An unsigned integer
+    Uint(u64),
+
+    /// A boolean
+    Bool(bool),
+}
+
+/// Write `st` as a quoted and escaped JSON string
+fn write_str(writer: &mut SerialWriter, st: &str) -> core::fmt::Result {
+    writer.write_char('"')?;
+    for chr in st.chars() {
+        match chr {
+            '"'  => writer.write_str("\\\"")?,
+            '\\' => writer.write_str("\\\\")?,
+            '\n' => writer.write_str("\\n")?,
+            '\r' => writer.write_str("\\r")?,
+            '\t' => writer.write_str("\\t")?,
+            _ if (chr as u32) < 0x20 =>
+                write!(writer, "\\u{:04x}", chr as u32)?,
+            _ => writer.write_char(chr)?,
+        }
+    }
+    writer.write_char('"')
+}
+
+/// Emit a boot event named `tag` with `fields` as a single line of JSON. The
+/// current TSC is always included as `ts_tsc`.
+pub fn emit_event(tag: &str, fields: &[(&str, JsonValue)]) {
+    // Hold the print lock such that events are never interleaved with other
+    // output
+    let _lock = crate::BOOT_ARGS.print_lock.lock();
+    let writer = &mut SerialWriter;
+
+    let _ = (|| -> core::fmt::Result {
+        writer.write_str("{\"tag\":")?;
+        write_str(writer, tag)?;
+        write!(writer, ",\"ts_tsc\":{}", cpu::rdtsc())?;
+
+        for &(name, value) in fields {
+            writer.write_char(',')?;
+            write_str(writer, name)?;
+            writer.write_char(':')?;
+
+            match value {
+                JsonValue::Str(val)  => write_str(writer, val)?,
+                JsonValue::Uint(val) => write!(writer, "{}", val)?,
+                JsonValue::Bool(val) => write!(writer, "{}", val)?,
+            }
+        }
+
+        writer.write_str("}\n")
+    })();
+}
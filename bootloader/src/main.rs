@@ -16,14 +16,26 @@ mod mm;
 mod panic;
 mod pxe;
 mod intrins;
+mod time;
+mod acpi;
+mod wdat;
+mod platform;
+mod selftest;
+mod json_log;
+mod apic;
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use serial::SerialPort;
 use boot_args::{BootArgs, KERNEL_PHYS_WINDOW_SIZE, KERNEL_STACKS_BASE};
 use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_STACK_SIZE, KERNEL_STACK_PAD};
+use boot_args::{KERNEL_INITRD_BASE, MAX_STACK_REGION_SIZE, ApApicIds};
+use boot_args::{KernelSymbolTable, E820Map, CpuTopology, ApEntrySpin};
+use boot_args::{KERNEL_HEAP_BASE, KERNEL_HEAP_SIZE, DEFAULT_REBOOT_DELAY_SECS};
 use pe_parser::PeParser;
 use lockcell::LockCell;
+use json_log::JsonValue;
 use page_table::{VirtAddr, PageType, PageTable, PAGE_PRESENT, PAGE_WRITE};
+use page_table::{PAGE_NX, PAGE_SIZE, largest_fitting_page};
 
 /// Global arguments shared between the kernel and bootloader. It is critical
 /// that every structure in here is identical in shape between both 64-bit
@@ -36,6 +48,29 @@ pub static BOOT_ARGS: BootArgs = BootArgs {
     kernel_entry:          LockCell::new(None),
     stack_vaddr:           AtomicU64::new(KERNEL_STACKS_BASE),
     print_lock:            LockCell::new(()),
+    cpu_barrier:           AtomicU32::new(0),
+    initrd_base:           AtomicU64::new(0),
+    initrd_size:           AtomicU64::new(0),
+    phys_window_base:      KERNEL_PHYS_WINDOW_BASE,
+    page_slabs:            AtomicU64::new(0),
+    online_cpus:           AtomicU32::new(0),
+    bsp_apic_id:           AtomicU32::new(0),
+    ap_apic_ids:           ApApicIds::new(),
+    kernel_symbols:        LockCell::new(None),
+    watchdog:              LockCell::new(None),
+    selftest_passed:       AtomicBool::new(false),
+    pxe_info:              LockCell::new(None),
+    e820:                  LockCell::new(E820Map::new()),
+    pxe_timeout:           AtomicBool::new(false),
+    pxe_network_info:      LockCell::new(None),
+    cpu_topology:          LockCell::new(CpuTopology::new()),
+    heap_base:             AtomicU64::new(0),
+    heap_size:             AtomicU64::new(0),
+    physical_window_pg_table: AtomicU64::new(0),
+    ap_entry_spin:         ApEntrySpin::new(),
+    kernel_phys_window_size: AtomicU64::new(0),
+    reboot_delay_secs:     AtomicU32::new(DEFAULT_REBOOT_DELAY_SECS),
+    tsc_hz:                AtomicU64::new(0),
 };
 
 /// Rust entry point for the bootloader
@@ -43,14 +78,22 @@ pub static BOOT_ARGS: BootArgs = BootArgs {
 /// * `bootloader_end` - One byte past the end of the bootloader
 #[no_mangle]
 extern fn entry(bootloader_end: usize) -> ! {
+    // Let everyone know which CPU is the BSP, before anything checks
+    if cpu::is_bsp() {
+        BOOT_ARGS.bsp_apic_id.store(cpu::apic_id(), Ordering::SeqCst);
+    }
+
     // Initialize the serial driver
     {
         // Get access to the serial driver
         let mut serial = BOOT_ARGS.serial.lock();
 
         if serial.is_none() {
-            // Driver has not yet been set up, initialize the ports
-            *serial = Some(unsafe { SerialPort::new() });
+            // Driver has not yet been set up, use the first port which
+            // works, falling back to the ports the BIOS knows about
+            *serial = Some(unsafe {
+                SerialPort::detect_first().unwrap_or_else(|| SerialPort::new())
+            });
 
             // "clear" the screen
             core::mem::drop(serial);
@@ -60,11 +103,42 @@ extern fn entry(bootloader_end: usize) -> ! {
     
             print!("Chocolate Milk bootloader starting...\n");
             print!("Bootloader end at {:#x}\n", bootloader_end);
+
+            // Make sure stage0 didn't cut the image short, otherwise our own
+            // data would be treated as free memory. Everything after the end
+            // of the data is discardable, such as DWARF debug info.
+            debug_assert!({
+                extern {
+                    /// End of the bootloader data and bss, defined in
+                    /// `asm_routines.asm`
+                    static __bootloader_data_end: u8;
+                    static __bootloader_bss_end:  u8;
+                }
+
+                let data_end = unsafe { core::cmp::max(
+                    &__bootloader_data_end as *const u8 as usize,
+                    &__bootloader_bss_end  as *const u8 as usize)
+                };
+                data_end <= bootloader_end
+            }, "stage0 bootloader_end is before the end of the image");
+
+            // Make sure the kernel will see `BootArgs` the same as we do
+            BOOT_ARGS.check_layout();
+
+            json_log::emit_event("serial_init", &[
+                ("bootloader_end", JsonValue::Uint(bootloader_end as u64)),
+            ]);
         }
     }
 
     // Initialize the MMU
-    mm::init();
+    mm::init(|args| serial_print!("{}", args));
+
+    if BOOT_ARGS.is_bsp() {
+        json_log::emit_event("mm_init", &[
+            ("max_phys_addr", JsonValue::Uint(mm::max_phys_addr())),
+        ]);
+    }
 
     // Download the kernel and create the kernel page table
     let (entry_point, stack, cr3, tramp_cr3) = {
@@ -72,56 +146,239 @@ extern fn entry(bootloader_end: usize) -> ! {
         let mut page_table   = BOOT_ARGS.page_table.lock();
         let mut tramp_table  = BOOT_ARGS.trampoline_page_table.lock();
 
-        // If no kernel entry is set yet, download the kernel and load it
-        if kernel_entry.is_none() {
+        // The BSP downloads the kernel and loads it. APs are only launched
+        // once the kernel is running, at which point it has been loaded.
+        if BOOT_ARGS.is_bsp() {
+            assert!(kernel_entry.is_none(), "BSP entered bootloader twice!?");
             assert!(page_table.is_none() && tramp_table.is_none(),
                 "Page tables set up before kernel!?");
 
+            // Check the hardware for problems before we go any further
+            selftest::run();
+
+            // Warn if the CPU is already running hot before the download
+            if let Some(temp) = platform::cpu_temperature_msr() {
+                if temp > 80 {
+                    print!("WARNING: CPU temperature is {} C\n", temp);
+                }
+            }
+
+            // Find the hardware watchdog, such that we can keep it from
+            // firing during the download
+            *BOOT_ARGS.watchdog.lock() = wdat::init();
+
+            // Allow `boot.cfg` to point us at a different TFTP server than
+            // the one DHCP gave us, and to configure the crash reboot delay
+            pxe::load_boot_cfg();
+            if let Some(ip) = pxe::server_ip() {
+                print!("TFTP server is {}.{}.{}.{}\n",
+                       ip[0], ip[1], ip[2], ip[3]);
+            }
+
+            // We can't tag frames ourselves, warn if DHCP expects us to
+            if let Some(vlan) = pxe::vlan_id() {
+                print!("WARNING: DHCP assigned VLAN {}, VLAN tagging must be \
+                        configured on the NIC or switch\n", vlan);
+            }
+
+            // Save the network configuration for the kernel
+            *BOOT_ARGS.pxe_info.lock() = pxe::server_info();
+            *BOOT_ARGS.pxe_network_info.lock() = pxe::network_info();
+
+            // Report how large of a kernel we could possibly load, as
+            // downloads need to be physically contiguous
+            {
+                let mut pmem = BOOT_ARGS.free_memory.lock();
+                let pmem = mm::PhysicalMemory(pmem.as_mut().unwrap());
+
+                let (base, len) = pmem.largest_contiguous_free_block();
+                print!("Largest free block is {} KB at {:#x}, {} KB free\n",
+                       len / 1024, base, pmem.total_free_bytes() / 1024);
+            }
+
             // Download the kernel
-            let kernel = pxe::download("chocolate_milk.kern")
+            json_log::emit_event("kernel_download_start", &[
+                ("filename", JsonValue::Str("chocolate_milk.kern")),
+            ]);
+            let kernel_size = pxe::file_size("chocolate_milk.kern")
+                .expect("Failed to get the size of chocolate_milk.kern");
+
+            // Allocate whole pages to download the kernel directly into, such
+            // that it's never held in the heap
+            let kernel_alloc = (kernel_size + 0xfff) & !0xfff;
+            let kernel_paddr = BOOT_ARGS.free_memory.lock().as_mut().unwrap()
+                .allocate(kernel_alloc, 4096)
+                .expect("Failed to allocate physical memory for kernel") as u64;
+
+            let (kernel_len, elapsed) = pxe::download_to_phys_timed(
+                "chocolate_milk.kern", kernel_paddr, kernel_alloc)
                 .expect("Failed to download chocolate_milk.kern over TFTP");
+            let kernel = unsafe {
+                core::slice::from_raw_parts(kernel_paddr as usize as *const u8,
+                                            kernel_len as usize)
+            };
+
+            // Report the download speed
+            let elapsed_ms = core::cmp::max(elapsed / 1_000_000, 1);
+            print!("Downloaded {} KB in {}.{}s ({} KB/s)\n",
+                kernel.len() / 1024, elapsed_ms / 1000,
+                elapsed_ms % 1000 / 100,
+                kernel.len() as u64 * 1000 / 1024 / elapsed_ms);
+
+            json_log::emit_event("kernel_download_done", &[
+                ("size",       JsonValue::Uint(kernel.len() as u64)),
+                ("elapsed_ns", JsonValue::Uint(elapsed)),
+            ]);
+
+            // Download the initrd, if the TFTP server has one for us. The
+            // initrd is optional, so just boot without one if it's missing.
+            // It's downloaded directly into whole zeroed pages, such that
+            // nothing else shares the pages we map into the kernel, and
+            // nothing stale is visible past its end.
+            let initrd = if pxe::exists("initrd.img") {
+                let size = pxe::file_size("initrd.img")
+                    .expect("Failed to get the size of initrd.img");
+                let alloc = (size + 0xfff) & !0xfff;
+
+                if alloc > 0 {
+                    let paddr = mm::PhysicalMemory(
+                        BOOT_ARGS.free_memory.lock().as_mut().unwrap())
+                        .alloc_and_zero(alloc)
+                        .expect("Failed to allocate physical memory for \
+                                 initrd");
+                    let size = pxe::download_to_phys("initrd.img", paddr,
+                                                     alloc)
+                        .expect("Failed to download initrd.img over TFTP");
+                    Some((paddr, size))
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
 
             // Parse the PE from the kernel
-            let pe = PeParser::parse(&kernel).expect("Failed to parse PE");
+            let pe = PeParser::parse_with_max_size(kernel, 256 * 1024 * 1024)
+                .expect("Failed to parse PE");
+
+            // Make sure the kernel was not truncated or padded in transit
+            assert!(pe.verify_checksum(), "Kernel PE checksum mismatch");
 
-            // Get exclusive access to physical memory
+            json_log::emit_event("pe_parse_done", &[
+                ("entry_point", JsonValue::Uint(pe.entry_point)),
+                ("image_base",  JsonValue::Uint(pe.image_base())),
+            ]);
+
+            // Get exclusive access to physical memory. The heap is backed by
+            // the same lock, thus no heap object may be dropped while `pmem`
+            // is held, as freeing it would deadlock.
             let mut pmem = BOOT_ARGS.free_memory.lock();
             let pmem = pmem.as_mut()
                 .expect("Whoa, physical memory not initialized yet");
             let mut pmem = mm::PhysicalMemory(pmem);
             
             // Create the trampoline page table
-            let mut trampoline_table = PageTable::new(&mut pmem);
+            let mut trampoline_table = PageTable::new(&mut pmem)
+                .expect("Failed to create trampoline page table");
 
             // Create the 2 different physical map windows for the trampoline
             // page table
             for paddr in (0..bootloader_end as u64).step_by(4096) {
                 unsafe {
                     // Create a mapping where vaddr == paddr
-                    trampoline_table.map_raw(
+                    let old = trampoline_table.map_raw(
                         &mut pmem, VirtAddr(paddr), PageType::Page4K,
-                        paddr | PAGE_WRITE | PAGE_PRESENT).unwrap();
+                        paddr | PAGE_WRITE | PAGE_PRESENT)
+                        .expect("Failed to map trampoline page table");
+                    assert!(old.is_none(), "duplicate mapping!");
 
                     // Create a mapping where
-                    // vaddr == (paddr + KERNEL_PHYS_WINDOW_BASE)
-                    trampoline_table.map_raw(
-                        &mut pmem, VirtAddr(KERNEL_PHYS_WINDOW_BASE + paddr),
+                    // vaddr == (paddr + phys_window_base)
+                    let old = trampoline_table.map_raw(
+                        &mut pmem,
+                        BOOT_ARGS.phys_to_virt(paddr),
                         PageType::Page4K,
-                        paddr | PAGE_WRITE | PAGE_PRESENT).unwrap();
+                        paddr | PAGE_WRITE | PAGE_PRESENT)
+                        .expect("Failed to map trampoline page table");
+                    assert!(old.is_none(), "duplicate mapping!");
                 }
             }
 
+            // Make sure nothing running on the trampoline table can modify
+            // the bootloader code
+            mm::reserve_bootloader_text_as_ro(&mut trampoline_table,
+                                              &mut pmem);
+
             // Create a new page table
-            let mut table = PageTable::new(&mut pmem);
+            let mut table = PageTable::new(&mut pmem)
+                .expect("Failed to create kernel page table");
+
+            // Only map the physical window up to the highest physical
+            // address the BIOS reported, page aligned, and let the kernel
+            // know how much of the window we mapped
+            BOOT_ARGS.kernel_phys_window_size.store(
+                core::cmp::min(KERNEL_PHYS_WINDOW_SIZE,
+                    mm::max_phys_addr().checked_add(0xfff)
+                        .expect("Integer overflow on physical window size") &
+                    !0xfff), Ordering::SeqCst);
+            let window_size =
+                BOOT_ARGS.kernel_phys_window_size.load(Ordering::SeqCst);
+
+            // Use the largest pages the CPU supports for the physical window
+            let max_page = if platform::supports_1g_pages() {
+                PageType::Page1G
+            } else {
+                PageType::Page2M
+            };
+
+            // Large pages must not span multiple memory types, as that is
+            // undefined behavior (Intel SDM 11.11.9). Thus they are split at
+            // E820 boundaries, which catches MMIO holes, and MTRR boundaries.
+            let mtrrs = platform::MtrrRanges::read();
+            let e820  = BOOT_ARGS.e820.lock();
+            let boundary_within = |start: u64, end: u64| {
+                mtrrs.boundary_within(start, end) ||
+                    e820.entries().iter().any(|entry| {
+                        let entry_end = entry.base.saturating_add(entry.size);
+                        (entry.base > start && entry.base < end) ||
+                            (entry_end > start && entry_end < end)
+                    })
+            };
 
             // Create a linear map of physical memory
-            for paddr in (0..KERNEL_PHYS_WINDOW_SIZE).step_by(4096) {
+            let mut paddr = 0;
+            while paddr < window_size {
+                let vaddr = BOOT_ARGS.phys_to_virt(paddr);
+                let mut page_type = core::cmp::min(max_page,
+                    largest_fitting_page(vaddr, paddr, window_size - paddr));
+                while page_type != PageType::Page4K &&
+                        boundary_within(paddr, paddr + page_type as u64) {
+                    page_type = match page_type {
+                        PageType::Page1G => PageType::Page2M,
+                        _                => PageType::Page4K,
+                    };
+                }
+                let size_bit =
+                    if page_type != PageType::Page4K { PAGE_SIZE } else { 0 };
+
                 unsafe {
-                    table.map_raw(&mut pmem,
-                        VirtAddr(KERNEL_PHYS_WINDOW_BASE + paddr),
-                        PageType::Page4K,
-                        paddr | PAGE_WRITE | PAGE_PRESENT).unwrap();
+                    let old = table.map_raw(&mut pmem, vaddr, page_type,
+                        paddr | size_bit | PAGE_WRITE | PAGE_PRESENT)
+                        .expect("Failed to map physical window");
+                    assert!(old.is_none(), "duplicate mapping!");
                 }
+
+                paddr += page_type as u64;
+            }
+            drop(e820);
+
+            // Map the local APIC and IOAPIC into the physical window as
+            // uncacheable. They're typically above the highest RAM address,
+            // and thus are not covered by the window. If they are, their
+            // existing mappings are made uncacheable.
+            for &mmio in &[platform::local_apic_base(), platform::IOAPIC_BASE] {
+                table.map_uncacheable(&mut pmem, BOOT_ARGS.phys_to_virt(mmio),
+                    mmio).expect("Failed to map APIC MMIO");
             }
 
             // Load all the sections from the PE into the new page table
@@ -129,12 +386,11 @@ extern fn entry(bootloader_end: usize) -> ! {
                 // Create a new virtual mapping for the PE range and initialize
                 // it to the raw bytes from the PE file, otherwise to zero for
                 // all bytes that were not initialized in the file.
-                table.map_init(&mut pmem, VirtAddr(vaddr),
+                table.map_init_slice(&mut pmem, VirtAddr(vaddr),
                     PageType::Page4K,
-                    vsize as u64, read, write, execute,
-                    Some(|off| {
-                        raw.get(off as usize).copied().unwrap_or(0)
-                    }));
+                    raw, (vsize as u64).checked_sub(raw.len() as u64)?,
+                    read, write, execute)
+                    .expect("Failed to map PE section");
 
                 print!("Created map at {:#018x} for {:#018x} bytes | \
                        perms {}{}{}\n",
@@ -146,19 +402,77 @@ extern fn entry(bootloader_end: usize) -> ! {
                 Some(())
             }).unwrap();
 
+            // In debug builds, make sure every section actually made it into
+            // the page table
+            #[cfg(debug_assertions)]
+            {
+                pe.sections(|vaddr, vsize, raw, _, _, _| {
+                    assert!(table.verify_sections(&mut pmem, VirtAddr(vaddr),
+                        vsize as u64,
+                        |off| raw.get(off as usize).copied().unwrap_or(0)),
+                        "Section at {:#x} was not loaded correctly", vaddr);
+                    Some(())
+                }).unwrap();
+            }
+
             print!("Entry point is {:#x}\n", pe.entry_point);
 
+            // Let the kernel know where its exports are
+            if let Some((export_dir, export_size)) = pe.export_directory() {
+                *BOOT_ARGS.kernel_symbols.lock() = Some(KernelSymbolTable {
+                    image_base:  pe.image_base(),
+                    export_dir:  export_dir,
+                    export_size: export_size as u64,
+                });
+            }
+
+            // Load the initrd, if we got one
+            if let Some((paddr, size)) = initrd {
+                // Map the initrd into the kernel as read-only
+                for offset in (0..size).step_by(4096) {
+                    unsafe {
+                        let old = table.map_raw(&mut pmem,
+                            VirtAddr(KERNEL_INITRD_BASE + offset),
+                            PageType::Page4K,
+                            (paddr + offset) | PAGE_NX | PAGE_PRESENT)
+                            .expect("Failed to map initrd");
+                        assert!(old.is_none(), "duplicate mapping!");
+                    }
+                }
+
+                print!("Loaded initrd at {:#x} for {:#x} bytes\n",
+                       KERNEL_INITRD_BASE, size);
+
+                BOOT_ARGS.initrd_base.store(KERNEL_INITRD_BASE,
+                                            Ordering::SeqCst);
+                BOOT_ARGS.initrd_size.store(size, Ordering::SeqCst);
+            }
+
+            // Map in the kernel heap as zeroed, writable, non-executable
+            // memory, such that the kernel can allocate from it without
+            // needing to modify the page table
+            table.map_init_slice(&mut pmem, VirtAddr(KERNEL_HEAP_BASE),
+                PageType::Page4K, &[], KERNEL_HEAP_SIZE, true, true, false)
+                .expect("Failed to map kernel heap");
+            BOOT_ARGS.heap_base.store(KERNEL_HEAP_BASE, Ordering::SeqCst);
+            BOOT_ARGS.heap_size.store(KERNEL_HEAP_SIZE, Ordering::SeqCst);
+
+            print!("Kernel page table uses {} KB\n",
+                   table.memory_usage(&mut pmem) / 1024);
+
             // Set up the entry point and page table
             *kernel_entry = Some(pe.entry_point);
             *tramp_table  = Some(trampoline_table);
             *page_table   = Some(table);
+
+            // The kernel has been loaded into the page table, we no longer
+            // need the downloaded image
+            pmem.free(kernel_paddr, kernel_alloc);
         }
 
-        // Get exclusive access to physical memory
-        let mut pmem = BOOT_ARGS.free_memory.lock();
-        let pmem = pmem.as_mut()
-            .expect("Whoa, physical memory not initialized yet");
-        let mut pmem = mm::PhysicalMemory(pmem);
+        // Allocate from this CPU's page slab, such that cores coming online
+        // at the same time don't fight over the physical memory lock
+        let mut pmem = mm::SlabPhysicalMemory;
 
         // At this point the page table is always set up
         let page_table = page_table.as_mut().unwrap();
@@ -166,28 +480,94 @@ extern fn entry(bootloader_end: usize) -> ! {
         // Get a unique stack address for this core
         let stack_addr = BOOT_ARGS.stack_vaddr.fetch_add(
             KERNEL_STACK_SIZE + KERNEL_STACK_PAD, Ordering::SeqCst);
+
+        // Make sure we haven't run out of the stack region
+        assert!(stack_addr + KERNEL_STACK_SIZE <=
+                KERNEL_STACKS_BASE + MAX_STACK_REGION_SIZE,
+                "Out of virtual space for kernel stacks");
         
         // Map in the stack
-        page_table.map(&mut pmem,
-                       VirtAddr(stack_addr), PageType::Page4K,
-                       KERNEL_STACK_SIZE, true, true, false).unwrap();
+        page_table.map_range_checked(&mut pmem,
+            VirtAddr(stack_addr), PageType::Page4K,
+            KERNEL_STACK_SIZE, true, true, false)
+            .expect("Failed to map kernel stack");
+
+        // The padding after our stack is directly below the next core's
+        // stack, guard the page below it such that it can never be mapped
+        page_table.map_guard_page(&mut pmem, VirtAddr(
+            stack_addr + KERNEL_STACK_SIZE + KERNEL_STACK_PAD - 4096))
+            .expect("Failed to map kernel stack guard page");
+
+        // `enter64` sets up the kernel's stack frame as if it was called,
+        // which requires the stack to be 16-byte aligned. Like SysV, the MSVC
+        // x64 ABI requires `rsp` to be 16-byte aligned prior to a `call`.
+        let stack = (stack_addr + KERNEL_STACK_SIZE) & !0xf;
+        debug_assert!(stack & 0xf == 0, "Kernel stack is not 16-byte aligned");
 
         (
             *kernel_entry.as_ref().unwrap(),
-            stack_addr + KERNEL_STACK_SIZE,
-            page_table.table().0 as u32,
-            tramp_table.as_ref().unwrap().table().0 as u32,
+            stack,
+            page_table.table_with_pcid(0) as u32,
+            tramp_table.as_ref().unwrap().table_with_pcid(0) as u32,
         )
     };
 
+    let entry_point = if BOOT_ARGS.is_bsp() {
+        // The kernel launches the APs, set up the barrier for every AP to
+        // check in at once it is done with the shared bootloader state. This
+        // must be done before entering the kernel, as the APs are started
+        // from there.
+        let cpus = acpi::count_cpus().unwrap_or(1);
+        BOOT_ARGS.cpu_barrier.store(cpus.saturating_sub(1), Ordering::SeqCst);
+
+        // Let the kernel bound its wait for the APs in time
+        BOOT_ARGS.tsc_hz.store(time::tsc_hz(), Ordering::SeqCst);
+
+        // Give the kernel a table of the memory which is still free
+        BOOT_ARGS.physical_window_pg_table.store(
+            mm::build_free_memory_page_table().0, Ordering::SeqCst);
+
+        entry_point
+    } else {
+        // Wait for the kernel to finish its global initialization and release
+        // us into it
+        BOOT_ARGS.ap_entry_spin.wait(cpu::apic_id())
+    };
+
+    // Make sure this CPU can actually enter long mode
+    platform::check_long_mode_support();
+
+    // Note that this CPU is going online in the kernel, and record its APIC
+    // ID in the slot for its boot order
+    let cpu_num = BOOT_ARGS.online_cpus.fetch_add(1, Ordering::SeqCst);
+    if let Some(slot) = BOOT_ARGS.ap_apic_ids.get(cpu_num as usize) {
+        slot.store(cpu::apic_id(), Ordering::SeqCst);
+    }
+    BOOT_ARGS.cpu_topology.lock().add(platform::cpu_topology());
+
+    json_log::emit_event("enter64", &[
+        ("apic_id", JsonValue::Uint(cpu::apic_id() as u64)),
+        ("bsp",     JsonValue::Bool(BOOT_ARGS.is_bsp())),
+    ]);
+
     extern {
         fn enter64(entry_point: u64, stack: u64, param: u64, cr3: u32,
                    tramp_cr3: u32, phys_window_base: u64) -> !;
     }
 
+    // We're done with all of the shared bootloader state, check in such that
+    // the kernel knows it is no longer in use
+    if !BOOT_ARGS.is_bsp() {
+        BOOT_ARGS.cpu_checkin();
+    }
+
     unsafe {
-        enter64(entry_point, stack, &BOOT_ARGS as *const BootArgs as u64,
-                cr3, tramp_cr3, KERNEL_PHYS_WINDOW_BASE);
+        // Pass the kernel the boot arguments as they are seen through the
+        // physical window
+        enter64(entry_point, stack,
+                BOOT_ARGS.phys_to_virt(
+                    &BOOT_ARGS as *const BootArgs as u64).0,
+                cr3, tramp_cr3, BOOT_ARGS.phys_window_base);
     }
 }
 
@@ -1,48 +1,370 @@
 //! Memory management routines for the bootloader allocator
 
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::alloc::{GlobalAlloc, Layout};
+use alloc::vec::Vec;
 
 use crate::realmode::{RegisterState, invoke_realmode};
 
 use crate::BOOT_ARGS;
-use page_table::{PhysAddr, PhysMem};
+use page_table::{PhysAddr, PhysMem, PageTable, VirtAddr, PageType};
+use page_table::{PAGE_PRESENT, PAGE_NX, PAGE_SIZE, largest_fitting_page};
+use boot_args::{PageSlab, MAX_APIC_IDS, PAGE_SLAB_SIZE, PAGE_SLAB_LOW};
+use boot_args::KERNEL_PHYS_WINDOW_SIZE;
 use rangeset::{Range, RangeSet};
+use lockcell::LockCell;
+
+/// All memory which was reported as usable RAM by the BIOS, regardless of
+/// whether it is currently free
+static USABLE_MEMORY: LockCell<RangeSet> = LockCell::new(RangeSet::new());
+
+/// One byte past the highest physical address reported by the BIOS in any
+/// E820 entry, regardless of type
+static MAX_PHYS_ADDR: AtomicU64 = AtomicU64::new(0);
+
+/// Get one byte past the highest physical address reported by the BIOS
+pub fn max_phys_addr() -> u64 {
+    MAX_PHYS_ADDR.load(Ordering::SeqCst)
+}
+
+/// Remove write access from the bootloader code in `table`, both from the
+/// identity map and from the physical window. Only whole pages of code are
+/// protected, as the image is not page aligned and code shares pages with
+/// the stage0 and data.
+pub fn reserve_bootloader_text_as_ro<P: PhysMem>(table: &mut PageTable,
+                                                 pmem: &mut P) {
+    extern {
+        /// Start of the bootloader image, defined by the linker. The PE
+        /// headers are immediately followed by the `.text` section, and are
+        /// never written to either, thus this is the start of the code.
+        static __ImageBase: u8;
+
+        /// End of the bootloader code, defined in `asm_routines.asm`
+        static __bootloader_text_end: u8;
+    }
+
+    // Get the whole pages of the code
+    let start = unsafe { &__ImageBase as *const u8 as u64 };
+    let end   = unsafe { &__bootloader_text_end as *const u8 as u64 };
+    let start = (start + 0xfff) & !0xfff;
+    let end   = end & !0xfff;
+    if start >= end {
+        return;
+    }
+
+    for &vaddr in &[VirtAddr(start), BOOT_ARGS.phys_to_virt(start)] {
+        table.remap_with_flags(pmem, vaddr, end - start, true, false, true)
+            .expect("Failed to write protect bootloader code");
+    }
+}
 
 /// A wrapper on a range set to allow implementing the `PhysMem` trait
 pub struct PhysicalMemory<'a>(pub &'a mut RangeSet);
 
+/// Translate a physical address for `size` bytes into a pointer which is
+/// accessible in the bootloader
+unsafe fn translate(paddr: PhysAddr, size: usize) -> *mut u8 {
+    assert!(size > 0, "Attempted to translate zero size memory");
+
+    // Convert the physical address into a `usize` which is addressable in
+    // the bootloader
+    let paddr: usize = paddr.0.try_into()
+        .expect("Physical address outside of addressible range");
+    let _pend: usize = paddr.checked_add(size - 1)
+        .expect("Integer overflow on physical address translation");
+
+    // At this point, `paddr` for `size` bytes fits in the 32-bit address
+    // space we have mapped in!
+    paddr as *mut u8
+}
+
+impl<'a> PhysicalMemory<'a> {
+    /// Allocate `size` bytes of page aligned physical memory and zero it,
+    /// returning the physical address of the allocation
+    pub fn alloc_and_zero(&mut self, size: u64) -> Option<u64> {
+        let alc = self.0.allocate(size, 4096)?;
+
+        // Zero out the allocation, such that no stale contents leak through
+        unsafe {
+            core::ptr::write_bytes(translate(PhysAddr(alc as u64),
+                size as usize), 0, size as usize);
+        }
+
+        Some(alc as u64)
+    }
+
+    /// Get the base physical address and length of the largest contiguous
+    /// range of free physical memory, which bounds the largest possible
+    /// allocation
+    pub fn largest_contiguous_free_block(&self) -> (u64, u64) {
+        self.0.entries().iter()
+            .map(|x| (x.start, (x.end - x.start).saturating_add(1)))
+            .max_by_key(|&(_, len)| len)
+            .unwrap_or((0, 0))
+    }
+
+    /// Get the total number of free bytes of physical memory
+    pub fn total_free_bytes(&self) -> u64 {
+        self.0.entries().iter()
+            .map(|x| (x.end - x.start).saturating_add(1))
+            .fold(0u64, |acc, x| acc.saturating_add(x))
+    }
+
+    /// Return `size` bytes of physical memory at `paddr` to the free memory
+    /// pool. Adjacent free ranges are merged with the freed range.
+    pub fn free(&mut self, paddr: u64, size: u64) {
+        // Nothing to free
+        if size == 0 {
+            return;
+        }
+
+        let end = size.checked_sub(1).and_then(|x| x.checked_add(paddr))
+            .expect("Integer overflow on free");
+
+        // Catch double frees
+        assert!(!self.0.contains(paddr) && !self.0.contains(end),
+            "Freed physical memory which was already free");
+
+        self.0.insert(Range { start: paddr, end: end });
+    }
+}
+
 impl<'a> PhysMem for PhysicalMemory<'a> {
     unsafe fn translate(&mut self, paddr: PhysAddr, size: usize) -> *mut u8 {
-        assert!(size > 0, "Attempted to translate zero size memory");
+        translate(paddr, size)
+    }
 
-        // Convert the physical address into a `usize` which is addressable in
-        // the bootloader
-        let paddr: usize = paddr.0.try_into()
-            .expect("Physical address outside of addressible range");
-        let _pend: usize = paddr.checked_add(size - 1)
-            .expect("Integer overflow on physical address translation");
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        self.0.allocate(layout.size() as u64, layout.align() as u64)
+            .map(|x| PhysAddr(x as u64))
+    }
 
-        // At this point, `paddr` for `size` bytes fits in the 32-bit address
-        // space we have mapped in!
-        paddr as *mut u8
+    fn free_phys(&mut self, addr: PhysAddr, size: u64) {
+        self.free(addr.0, size);
     }
 
-    fn alloc_phys(&mut self, layout: Layout) -> PhysAddr {
-        PhysAddr(
-            self.0.allocate(layout.size() as u64, layout.align() as u64)
-                .expect("Failed to allocate physical memory") as u64
-        )
+    fn is_valid_ram(&mut self, paddr: PhysAddr) -> bool {
+        USABLE_MEMORY.lock().contains(paddr.0)
+    }
+}
+
+/// Allocate `count` 4 KiB pages of physical memory, which are not necessarily
+/// contiguous, only acquiring the physical memory lock once. If not all of the
+/// pages could be allocated, none of them are.
+///
+/// This is not a method of `PhysicalMemory`, as the list of pages comes from
+/// the heap, which is backed by the same lock.
+pub fn alloc_pages_bulk(count: u64) -> Option<Vec<u64>> {
+    // Allocate room for the pages before taking the lock
+    let mut pages = Vec::with_capacity(count.try_into().ok()?);
+
+    // The lock is declared after `pages`, thus it's always released before
+    // `pages` is freed
+    let mut pmem = BOOT_ARGS.free_memory.lock();
+    let pmem = pmem.as_mut()?;
+
+    for _ in 0..count {
+        match pmem.allocate(4096, 4096) {
+            Some(page) => pages.push(page as u64),
+            None => {
+                // Give back the pages we did get
+                let mut pmem = PhysicalMemory(pmem);
+                for &page in &pages {
+                    pmem.free(page, 4096);
+                }
+                return None;
+            }
+        }
+    }
+
+    Some(pages)
+}
+
+/// A `PhysMem` implementation which services 4 KiB page allocations from the
+/// current CPU's `PageSlab`, only acquiring the global physical memory lock
+/// when the slab needs to be replenished
+pub struct SlabPhysicalMemory;
+
+impl SlabPhysicalMemory {
+    /// Get the page slab for the current CPU
+    fn slab(&self) -> &'static PageSlab {
+        let slabs = BOOT_ARGS.page_slabs.load(Ordering::SeqCst);
+        assert!(slabs != 0, "Page slabs not initialized yet");
+
+        let apic_id = cpu::apic_id() as usize;
+        assert!(apic_id < MAX_APIC_IDS, "APIC ID too large for page slabs");
+
+        unsafe { &*(slabs as *const PageSlab).add(apic_id) }
+    }
+}
+
+impl PhysMem for SlabPhysicalMemory {
+    unsafe fn translate(&mut self, paddr: PhysAddr, size: usize) -> *mut u8 {
+        translate(paddr, size)
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        // Only single pages come from the slab
+        if layout.size() != 4096 || layout.align() != 4096 {
+            let mut pmem = BOOT_ARGS.free_memory.lock();
+            return PhysicalMemory(pmem.as_mut().unwrap()).alloc_phys(layout);
+        }
+
+        let slab  = self.slab();
+        let count = slab.count.load(Ordering::SeqCst) as usize;
+
+        // Replenish the slab with a batch of pages if it is running low
+        let count = if count <= PAGE_SLAB_LOW {
+            // Get a batch of pages from physical memory, they don't need to
+            // be contiguous. A failed batch gives back every page it got, so
+            // retry with smaller batches until we get at least one page. The
+            // lock is only held while the pages are allocated, not while
+            // they are put into the slab.
+            let mut batch = (PAGE_SLAB_SIZE - count) as u64;
+            let pages = loop {
+                if let Some(pages) = alloc_pages_bulk(batch) {
+                    break pages;
+                }
+
+                if batch <= 1 {
+                    break Vec::new();
+                }
+                batch /= 2;
+            };
+
+            // Put all the pages into the slab
+            for (slot, &page) in slab.pages[count..].iter().zip(&pages) {
+                slot.store(page, Ordering::SeqCst);
+            }
+
+            count + pages.len()
+        } else {
+            count
+        };
+
+        // Out of memory if we couldn't get a single page
+        if count == 0 {
+            return None;
+        }
+
+        // Take the last page from the slab
+        slab.count.store(count as u64 - 1, Ordering::SeqCst);
+        Some(PhysAddr(slab.pages[count - 1].load(Ordering::SeqCst)))
     }
 
     fn free_phys(&mut self, addr: PhysAddr, size: u64) {
-        let end = size.checked_sub(1).and_then(|x| x.checked_add(addr.0))
-            .expect("Integer overflow on free");
+        let slab  = self.slab();
+        let count = slab.count.load(Ordering::SeqCst) as usize;
 
-        self.0.insert(Range { start: addr.0, end: end });
+        if (addr.0 & 0xfff) == 0 && size == 4096 && count < PAGE_SLAB_SIZE {
+            // Put the page back in the slab
+            slab.pages[count].store(addr.0, Ordering::SeqCst);
+            slab.count.store(count as u64 + 1, Ordering::SeqCst);
+        } else {
+            let mut pmem = BOOT_ARGS.free_memory.lock();
+            PhysicalMemory(pmem.as_mut().unwrap()).free_phys(addr, size);
+        }
     }
 }
 
+/// A `PhysMem` implementation which hands out pages from a fixed region of
+/// physical memory which was allocated up front, from `next` to `end`
+struct BumpPhysicalMemory {
+    next: u64,
+    end:  u64,
+}
+
+impl PhysMem for BumpPhysicalMemory {
+    unsafe fn translate(&mut self, paddr: PhysAddr, size: usize) -> *mut u8 {
+        translate(paddr, size)
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        assert!(layout.size() == 4096 && layout.align() == 4096,
+            "Only pages can be allocated from a bump allocator");
+        if self.next >= self.end {
+            return None;
+        }
+
+        let alc = self.next;
+        self.next += 4096;
+        Some(PhysAddr(alc))
+    }
+
+    fn free_phys(&mut self, _addr: PhysAddr, _size: u64) {
+        panic!("Cannot free to a bump allocator");
+    }
+}
+
+/// Build a page table which maps every page of free physical memory, and
+/// nothing else, at the address it has in the physical window. The kernel
+/// can determine if a page is free by checking if it is present in this
+/// table. The mappings are read-only and use the largest pages possible.
+///
+/// The tables are allocated before walking the free memory, thus they never
+/// map themselves. Tables which turn out not to be needed are freed
+/// afterwards, these few pages are free but not mapped. Returns the physical
+/// address of the PML4.
+pub fn build_free_memory_page_table() -> PhysAddr {
+    let mut pmem = BOOT_ARGS.free_memory.lock();
+    let pmem = pmem.as_mut().unwrap();
+
+    // Get an upper bound on the number of tables needed. Each free range
+    // needs at most a partial table at each end at every level, and all the
+    // PDPTs and PDs in between, as we might not have 1 GiB pages.
+    let tables = 1 + pmem.entries().iter().map(|ent| {
+        let size = ent.end - ent.start;
+        ((size >> 39) + 2) + ((size >> 30) + 2) + 2
+    }).sum::<u64>();
+
+    // Allocate the tables
+    let size = tables * 4096;
+    let alc = pmem.allocate(size, 4096)
+        .expect("Failed to allocate free memory page table") as u64;
+    let mut tables = BumpPhysicalMemory { next: alc, end: alc + size };
+
+    // Use the largest pages the CPU supports
+    let max_page = if crate::platform::supports_1g_pages() {
+        PageType::Page1G
+    } else {
+        PageType::Page2M
+    };
+
+    // Map all the whole pages in each free range which fall in the window
+    let mut table = PageTable::new(&mut tables)
+        .expect("Failed to allocate free memory page table");
+    for ent in pmem.entries() {
+        let start = (ent.start + 0xfff) & !0xfff;
+        let end   = core::cmp::min(ent.end.saturating_add(1) & !0xfff,
+                                   KERNEL_PHYS_WINDOW_SIZE);
+
+        let mut paddr = start;
+        while paddr < end {
+            let vaddr = BOOT_ARGS.phys_to_virt(paddr);
+            let page_type = core::cmp::min(max_page,
+                largest_fitting_page(vaddr, paddr, end - paddr));
+            let size_bit =
+                if page_type != PageType::Page4K { PAGE_SIZE } else { 0 };
+
+            unsafe {
+                table.map_raw(&mut tables, vaddr, page_type,
+                    paddr | size_bit | PAGE_NX | PAGE_PRESENT)
+                    .expect("Failed to map free memory");
+            }
+
+            paddr += page_type as u64;
+        }
+    }
+
+    // Give back the tables we didn't need
+    if tables.next < tables.end {
+        PhysicalMemory(pmem).free(tables.next, tables.end - tables.next);
+    }
+
+    table.table()
+}
+
 /// The global allocator for the bootloader, this just uses physical memory as
 /// a backing and does not handle any fancy things like fragmentation. Use this
 /// carefully.
@@ -83,7 +405,15 @@ fn alloc_error(_layout: Layout) -> ! {
 /// BIOS via E820 and put it into a `RangeSet` for tracking and allocation.
 /// We also subtract off the first 1 MiB of memory to prevent BIOS data
 /// structures from being overwritten.
-pub fn init() {
+///
+/// Progress is reported through `log`, including every E820 region and what
+/// was done with it.
+///
+/// There is no EFI memory map path. The bootloader is a flat 16-bit image run
+/// by the BIOS PXE stack at 0x7c00, and is never launched by UEFI firmware,
+/// thus there is never an EFI system table to get a memory map from. Booting
+/// from UEFI would need a separate 64-bit EFI application.
+pub fn init(log: impl Fn(core::fmt::Arguments)) {
     // Create a `RangeSet` to hold the memory that is marked free by the
     // BIOS
     let mut pmem = BOOT_ARGS.free_memory.lock();
@@ -136,6 +466,41 @@ pub fn init() {
                 panic!("Error reported by BIOS on E820");
             }
 
+            // Report the region
+            if add_free_mem {
+                log(format_args!("E820 {:#018x}-{:#018x} type {} | {}\n",
+                    entry.base, entry.base.saturating_add(entry.size),
+                    entry.typ,
+                    if entry.size == 0 {
+                        "empty, ignored"
+                    } else if entry.typ == 1 {
+                        "added to free list"
+                    } else {
+                        "reserved"
+                    }));
+            }
+
+            // Save the raw entry for the kernel
+            if add_free_mem {
+                let mut e820 = BOOT_ARGS.e820.lock();
+                let count = e820.count as usize;
+                if let Some(saved) = e820.entries.get_mut(count) {
+                    *saved = boot_args::E820Entry {
+                        base: entry.base,
+                        size: entry.size,
+                        typ:  entry.typ,
+                        _pad: 0,
+                    };
+                    e820.count += 1;
+                }
+            }
+
+            // Track the highest physical address the BIOS knows about
+            let end = entry.base.saturating_add(entry.size);
+            if add_free_mem && end > max_phys_addr() {
+                MAX_PHYS_ADDR.store(end, Ordering::SeqCst);
+            }
+
             if add_free_mem && entry.typ == 1 && entry.size > 0 {
                 // If the entry is free, mark the memory as free
                 free_memory.insert(Range {
@@ -158,6 +523,9 @@ pub fn init() {
         }
     }
 
+    // Save off all usable RAM, before we start reserving parts of it
+    *USABLE_MEMORY.lock() = free_memory;
+
     // Remove the first 1 MiB of memory for use. The BIOS does some weird stuff
     // we can't really trust the memory map in this area. Especially with
     // option ROMs potentially using some of this RAM.
@@ -166,6 +534,18 @@ pub fn init() {
         end:   1024 * 1024 - 1,
     });
 
+    // Allocate the per-CPU page slabs, all zeros marks them as empty
+    let slabs_size = core::mem::size_of::<PageSlab>() * MAX_APIC_IDS;
+    let slabs = free_memory.allocate(slabs_size as u64,
+        core::mem::align_of::<PageSlab>() as u64)
+        .expect("Failed to allocate page slabs");
+    unsafe { core::ptr::write_bytes(slabs as *mut u8, 0, slabs_size); }
+    BOOT_ARGS.page_slabs.store(slabs as u64, Ordering::SeqCst);
+
+    log(format_args!("{} MiB of usable RAM, {} MiB free\n",
+        USABLE_MEMORY.lock().sum().unwrap_or(0) / 1024 / 1024,
+        free_memory.sum().unwrap_or(0) / 1024 / 1024));
+
     // Set up the global physical memory state with the free memory we have
     // tracked.
     *pmem = Some(free_memory);
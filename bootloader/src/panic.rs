@@ -15,6 +15,23 @@ fn panic(info: &PanicInfo) -> ! {
 
     print!("\n");
 
+    #[cfg(feature = "crash-reboot")]
+    {
+        use core::sync::atomic::Ordering;
+
+        let delay = crate::BOOT_ARGS.reboot_delay_secs.load(Ordering::SeqCst);
+        print!("Rebooting in {} seconds...\n", delay);
+
+        // The PIT is used rather than the TSC, as calibrating the TSC takes
+        // a lock which we may have panicked while holding
+        for _ in 0..delay {
+            crate::time::pit_delay_ms(1000);
+        }
+
+        crate::platform::reboot();
+    }
+
+    #[cfg(not(feature = "crash-reboot"))]
     cpu::halt();
 }
 
@@ -0,0 +1,273 @@
+//! Platform health checks
+
+use boot_args::CpuTopoEntry;
+
+/// MSR containing the temperature at which the CPU is throttled, Tjmax. This
+/// is model specific, it only exists on the models in
+/// `TEMPERATURE_TARGET_MODELS`.
+const MSR_TEMPERATURE_TARGET: u32 = 0x1a2;
+
+/// Family 6 Intel models which are known to have `MSR_TEMPERATURE_TARGET`,
+/// Nehalem and newer Core and Xeon parts. Reading it on any other CPU may
+/// fault, and we have no IDT to recover from that.
+const TEMPERATURE_TARGET_MODELS: &[u32] = &[
+    0x1a, 0x1e, 0x1f, 0x2e,             // Nehalem
+    0x25, 0x2c, 0x2f,                   // Westmere
+    0x2a, 0x2d, 0x3a, 0x3e,             // Sandy Bridge, Ivy Bridge
+    0x3c, 0x3f, 0x45, 0x46,             // Haswell
+    0x3d, 0x47, 0x4f, 0x56,             // Broadwell
+    0x4e, 0x5e, 0x55, 0x8e, 0x9e,       // Skylake, Kaby Lake
+    0xa5, 0xa6,                         // Comet Lake
+    0x6a, 0x6c, 0x7d, 0x7e,             // Ice Lake
+    0x8c, 0x8d, 0xa7, 0x8f,             // Tiger Lake, Rocket Lake, SPR
+    0x97, 0x9a, 0xb7, 0xba, 0xbf,       // Alder Lake, Raptor Lake
+];
+
+/// Tjmax to assume in degrees Celsius when the CPU can't report it
+const DEFAULT_TJMAX: i32 = 100;
+
+/// MSR containing the thermal status of the current core
+const IA32_THERM_STATUS: u32 = 0x19c;
+
+/// MSR containing the physical base address of the local APIC
+const IA32_APIC_BASE: u32 = 0x1b;
+
+/// MSR reporting the number of variable range MTRRs and if fixed range MTRRs
+/// are supported
+const IA32_MTRRCAP: u32 = 0xfe;
+
+/// MSR holding the default memory type and the MTRR enables
+const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+
+/// MSR of the first variable range MTRR base, each base is followed by its
+/// mask
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+
+/// Maximum number of variable range MTRRs which are read
+const MAX_VARIABLE_MTRRS: usize = 32;
+
+/// Physical address of the IOAPIC on PC compatible platforms. We don't parse
+/// the MADT, thus we assume the IOAPIC is at this default location.
+pub const IOAPIC_BASE: u64 = 0xfec0_0000;
+
+/// Get the temperature of the current core in degrees Celsius from the
+/// digital thermal sensor. Returns `None` if this is not an Intel CPU or the
+/// CPU does not have a digital thermal sensor. On models which can't report
+/// their Tjmax, it is assumed to be `DEFAULT_TJMAX`.
+pub fn cpu_temperature_msr() -> Option<i32> {
+    unsafe {
+        // Make sure this is an Intel CPU
+        let (max_leaf, ebx, ecx, edx) = cpu::cpuid(0, 0);
+        if (ebx, edx, ecx) != (u32::from_le_bytes(*b"Genu"),
+                               u32::from_le_bytes(*b"ineI"),
+                               u32::from_le_bytes(*b"ntel")) {
+            return None;
+        }
+
+        // Check for digital thermal sensor support
+        if max_leaf < 6 || (cpu::cpuid(6, 0).0 & 1) == 0 {
+            return None;
+        }
+
+        // Make sure the thermal readout is valid
+        let status = cpu::rdmsr(IA32_THERM_STATUS);
+        if (status & (1 << 31)) == 0 {
+            return None;
+        }
+
+        // Get the display model, which includes the extended model for
+        // family 6
+        let signature = cpu::cpuid(1, 0).0;
+        let family    = (signature >> 8) & 0xf;
+        let model     = ((signature >> 4) & 0xf) |
+            (((signature >> 16) & 0xf) << 4);
+
+        // The readout is the number of degrees below Tjmax, only read Tjmax
+        // if the MSR is known to exist
+        let has_target =
+            family == 6 && TEMPERATURE_TARGET_MODELS.contains(&model);
+        let tjmax = if has_target {
+            ((cpu::rdmsr(MSR_TEMPERATURE_TARGET) >> 16) & 0xff) as i32
+        } else {
+            DEFAULT_TJMAX
+        };
+        let readout = ((status >> 16) & 0x7f) as i32;
+
+        Some(tjmax - readout)
+    }
+}
+
+/// Get the location of the current CPU in the CPU topology from CPUID leaf
+/// 0xb. If the leaf is not supported, every CPU is treated as its own package.
+pub fn cpu_topology() -> CpuTopoEntry {
+    unsafe {
+        // Number of bits of the x2APIC ID used by the thread and core IDs
+        let mut thread_bits = 0;
+        let mut core_bits   = 0;
+        let mut apic_id     = cpu::apic_id();
+
+        if cpu::cpuid(0, 0).0 >= 0xb && cpu::cpuid(0xb, 0).1 != 0 {
+            // Walk the levels, the level type in ecx[15:8] is 1 for SMT and 2
+            // for core, and each level reports the bits to shift the x2APIC
+            // ID right by to get the ID at the next level in eax[4:0]. The
+            // last level has ebx of zero.
+            for subleaf in 0..8 {
+                let (eax, ebx, ecx, edx) = cpu::cpuid(0xb, subleaf);
+                if ebx == 0 {
+                    break;
+                }
+
+                apic_id = edx;
+                match (ecx >> 8) & 0xff {
+                    1 => thread_bits = eax & 0x1f,
+                    2 => core_bits   = eax & 0x1f,
+                    _ => {}
+                }
+            }
+        }
+
+        // The core level shift includes the thread bits
+        let core_bits = core::cmp::max(core_bits, thread_bits);
+
+        CpuTopoEntry {
+            apic_id:    apic_id,
+            package_id: apic_id.checked_shr(core_bits).unwrap_or(0) as u8,
+            core_id:    ((apic_id & ((1 << core_bits) - 1)) >> thread_bits)
+                as u8,
+            thread_id:  (apic_id & ((1 << thread_bits) - 1)) as u8,
+            _pad:       0,
+        }
+    }
+}
+
+/// Get the physical base address of the current core's local APIC
+pub fn local_apic_base() -> u64 {
+    unsafe { cpu::rdmsr(IA32_APIC_BASE) & 0xf_ffff_f000 }
+}
+
+/// Returns true if the CPU supports 1 GiB pages
+pub fn supports_1g_pages() -> bool {
+    unsafe {
+        cpu::cpuid(0x8000_0000, 0).0 >= 0x8000_0001 &&
+            (cpu::cpuid(0x8000_0001, 0).3 & (1 << 26)) != 0
+    }
+}
+
+/// The physical memory ranges which have their memory type set by MTRRs
+pub struct MtrrRanges {
+    /// Set if the fixed range MTRRs are enabled, which set the memory type of
+    /// the first 1 MiB in ranges as small as 4 KiB
+    fixed: bool,
+
+    /// `(base, size)` of the enabled variable range MTRRs, only the first
+    /// `count` are valid
+    variable: [(u64, u64); MAX_VARIABLE_MTRRS],
+
+    /// Number of valid entries in `variable`
+    count: usize,
+}
+
+impl MtrrRanges {
+    /// Read the MTRRs of the current CPU
+    pub fn read() -> Self {
+        let mut ret = MtrrRanges {
+            fixed:    false,
+            variable: [(0, 0); MAX_VARIABLE_MTRRS],
+            count:    0,
+        };
+
+        unsafe {
+            // Check if the CPU has MTRRs at all, and if they're enabled.
+            // Otherwise all memory has the same type.
+            if (cpu::cpuid(1, 0).3 & (1 << 12)) == 0 {
+                return ret;
+            }
+            let cap      = cpu::rdmsr(IA32_MTRRCAP);
+            let def_type = cpu::rdmsr(IA32_MTRR_DEF_TYPE);
+            if (def_type & (1 << 11)) == 0 {
+                return ret;
+            }
+
+            ret.fixed = (cap & (1 << 8)) != 0 && (def_type & (1 << 10)) != 0;
+
+            for ii in 0..core::cmp::min((cap & 0xff) as usize,
+                                        MAX_VARIABLE_MTRRS) {
+                let msr  = IA32_MTRR_PHYSBASE0 + ii as u32 * 2;
+                let base = cpu::rdmsr(msr);
+                let mask = cpu::rdmsr(msr + 1);
+
+                // Skip MTRRs which are not valid
+                if (mask & (1 << 11)) == 0 {
+                    continue;
+                }
+
+                // The size of the range is the lowest set bit of the mask.
+                // This assumes the mask is contiguous, as non-contiguous
+                // masks are not something firmware sets up in practice.
+                let mask = mask & !0xfff;
+                if mask == 0 {
+                    continue;
+                }
+                let size = 1u64 << mask.trailing_zeros();
+
+                ret.variable[ret.count] = (base & !0xfff & !(size - 1), size);
+                ret.count += 1;
+            }
+        }
+
+        ret
+    }
+
+    /// Returns `true` if the memory type could change inside of the physical
+    /// range `[start, end)`
+    pub fn boundary_within(&self, start: u64, end: u64) -> bool {
+        // The fixed range MTRRs can change the type at any 4 KiB boundary
+        // below 1 MiB
+        if self.fixed && start < 1024 * 1024 && end - start > 4096 {
+            return true;
+        }
+
+        self.variable[..self.count].iter().any(|&(base, size)| {
+            let range_end = base.saturating_add(size);
+            (base > start && base < end) ||
+                (range_end > start && range_end < end)
+        })
+    }
+}
+
+/// Make sure the CPU supports everything `enter64` relies on to get into long
+/// mode. If anything is missing, this panics with a diagnostic rather than
+/// letting `enter64` triple fault.
+pub fn check_long_mode_support() {
+    unsafe {
+        // Check for PAE and FXSR
+        let features = cpu::cpuid(1, 0).3;
+        assert!((features & (1 <<  6)) != 0, "CPU does not support PAE");
+        assert!((features & (1 << 24)) != 0, "CPU does not support FXSR");
+
+        // Check for long mode and NX
+        assert!(cpu::cpuid(0x8000_0000, 0).0 >= 0x8000_0001,
+                "CPU does not support extended CPUID leaves");
+        let features = cpu::cpuid(0x8000_0001, 0).3;
+        assert!((features & (1 << 29)) != 0, "CPU does not support long mode");
+        assert!((features & (1 << 20)) != 0, "CPU does not support NX");
+    }
+}
+
+/// Reset the machine. The ACPI reset register is used if the platform has
+/// one, falling back to the reset control register at I/O port `0xcf9`.
+/// This is only used to reboot after a panic.
+#[cfg(feature = "crash-reboot")]
+pub fn reboot() -> ! {
+    if let Some(fadt) = crate::acpi::parse_fadt() {
+        fadt.reset();
+    }
+
+    unsafe {
+        // Request a hard reset through the reset control register
+        cpu::out8(0xcf9, 0x02);
+        cpu::out8(0xcf9, 0x06);
+    }
+
+    cpu::halt();
+}
@@ -1,5 +1,8 @@
 //! print macro support
 
+#[cfg(feature = "vga-fallback")]
+use lockcell::LockCell;
+
 /// Dummy type to implement `core::fmt::Write` for `print!` macros
 pub struct SerialWriter;
 
@@ -13,12 +16,129 @@ impl core::fmt::Write for SerialWriter {
     }
 }
 
+/// Width of the VGA text mode screen, in characters
+#[cfg(feature = "vga-fallback")]
+const VGA_WIDTH: usize = 80;
+
+/// Height of the VGA text mode screen, in characters
+#[cfg(feature = "vga-fallback")]
+const VGA_HEIGHT: usize = 25;
+
+/// Global VGA text mode writer, this is used by `print!` when the
+/// `vga-fallback` feature is enabled
+#[cfg(feature = "vga-fallback")]
+pub static VGA_WRITER: LockCell<VgaWriter> = LockCell::new(VgaWriter {
+    col: 0,
+    row: 0,
+});
+
+/// A writer for the 80x25 VGA text mode framebuffer at `0xb8000`, which keeps
+/// track of the current cursor position
+#[cfg(feature = "vga-fallback")]
+pub struct VgaWriter {
+    /// Current column of the cursor
+    col: u8,
+
+    /// Current row of the cursor
+    row: u8,
+}
+
+#[cfg(feature = "vga-fallback")]
+impl VgaWriter {
+    /// Write a byte to the screen at the current cursor position
+    fn write_byte(&mut self, byte: u8) {
+        // Get access to the framebuffer, this is identity mapped for us
+        let fb = 0xb8000 as *mut u16;
+
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.col = 0,
+            _ => {
+                // Write the character as white on black
+                let idx = self.row as usize * VGA_WIDTH + self.col as usize;
+                unsafe {
+                    core::ptr::write_volatile(fb.add(idx),
+                        0x0f00 | byte as u16);
+                }
+
+                // Advance the cursor, wrapping at the end of the line
+                self.col += 1;
+                if self.col as usize == VGA_WIDTH {
+                    self.newline();
+                }
+            }
+        }
+    }
+
+    /// Move the cursor to the start of the next line, scrolling the screen if
+    /// we're already on the last row
+    fn newline(&mut self) {
+        let fb = 0xb8000 as *mut u16;
+
+        self.col = 0;
+
+        if (self.row as usize) < VGA_HEIGHT - 1 {
+            // There are still rows left on the screen
+            self.row += 1;
+            return;
+        }
+
+        unsafe {
+            // Move every row up by one
+            for idx in 0..VGA_WIDTH * (VGA_HEIGHT - 1) {
+                core::ptr::write_volatile(fb.add(idx),
+                    core::ptr::read_volatile(fb.add(idx + VGA_WIDTH)));
+            }
+
+            // Blank out the last row
+            for idx in VGA_WIDTH * (VGA_HEIGHT - 1)..VGA_WIDTH * VGA_HEIGHT {
+                core::ptr::write_volatile(fb.add(idx), 0x0f00 | b' ' as u16);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "vga-fallback")]
+impl core::fmt::Write for VgaWriter {
+    fn write_str(&mut self, st: &str) -> core::fmt::Result {
+        for &byte in st.as_bytes() {
+            self.write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {{
         let _lock = $crate::BOOT_ARGS.print_lock.lock();
+
+        // Expand the arguments once, as they may have side effects. A `match`
+        // keeps the temporaries of `format_args!` alive, a `let` would not.
+        match format_args!($($arg)*) {
+            args => {
+                let _ = core::fmt::Write::write_fmt(
+                    &mut $crate::print::SerialWriter, args);
+
+                #[cfg(feature = "vga-fallback")]
+                let _ = core::fmt::Write::write_fmt(
+                    &mut *$crate::print::VGA_WRITER.lock(), args);
+            }
+        }
+    }}
+}
+
+/// Same as `print!`, but only takes the serial lock and not the global
+/// `print_lock`. This is safe to use while holding locks which the printing
+/// path may otherwise need, such as the physical memory lock.
+///
+/// As the print lock is not held, output from multiple CPUs using this at the
+/// same time may be interleaved, and it is not mirrored to VGA.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {{
         let _ = core::fmt::Write::write_fmt(
             &mut $crate::print::SerialWriter, format_args!($($arg)*));
     }}
 }
-
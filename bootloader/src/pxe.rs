@@ -1,29 +1,150 @@
 //! Routines for using the real-mode PXE APIs as provided by the BIOS and/or
 //! option ROMs
+//!
+//! The BIOS PXE API only provides IPv4 UDP and TFTP services, every parameter
+//! structure carries 4-byte IP addresses. IPv6 (DHCPv6 based) network booting
+//! is only specified for UEFI firmware, and thus is not supported here.
+//!
+//! 802.1Q VLAN tagging is not supported either. The TFTP and UDP services
+//! build and receive the Ethernet frames inside the PXE stack, thus there is
+//! no point at which we could insert or strip a tag. Doing so would require
+//! driving UNDI directly with our own IP, UDP, and TFTP implementation. If the
+//! TFTP server is only reachable over a VLAN, the VLAN must be configured in
+//! the NIC's option ROM, or on the switch port as the native VLAN. A VLAN ID
+//! handed out in DHCP option 132 is detected with `vlan_id` so the
+//! misconfiguration can be reported. This relies on the PXE stack exposing
+//! the cached DHCP ACK, with its options, via GET_CACHED_INFO.
 
 use core::convert::TryInto;
 use alloc::vec::Vec;
+use alloc::boxed::Box;
 
-use crate::realmode::{invoke_realmode, pxecall, RegisterState};
+use crate::realmode::{invoke_realmode, pxecall, pxe_api_call, RegisterState};
+use crate::realmode::{PxeStructure, pxe_struct_at, get_pxe_struct_address};
 
 use lockcell::LockCell;
+use boot_args::{PxeServerInfo, PxeNetworkInfo};
 
 /// A guard to prevent multiple uses of the PXE API at the same time
 static PXE_GUARD: LockCell<()> = LockCell::new(());
 
+/// TFTP server IP address to use instead of the one from DHCP, if set
+static NEXT_SERVER: LockCell<Option<[u8; 4]>> = LockCell::new(None);
+
+/// `chocolate_milk.kern` as received over UDP broadcast, if `boot.cfg` asked
+/// for it to be
+static BROADCAST_KERNEL: LockCell<Option<&'static [u8]>> = LockCell::new(None);
+
+/// Number of seconds a TFTP download may take on top of the time it takes at
+/// `TFTP_MIN_BYTES_PER_SEC`
+const TFTP_TIMEOUT_SECS: u64 = 10;
+
+/// Slowest transfer rate at which a TFTP download is not given up on
+const TFTP_MIN_BYTES_PER_SEC: u64 = 64 * 1024;
+
+/// Number of milliseconds without any packets after which a UDP broadcast
+/// download is given up on
+const BROADCAST_IDLE_TIMEOUT_MS: u32 = 5000;
+
+/// Number of bytes of data in every UDP broadcast packet but the last, such
+/// that a packet fits in an unfragmented Ethernet frame along with its 8 byte
+/// header
+const BROADCAST_CHUNK_SIZE: usize = 1464;
+
+/// Largest file we accept over UDP broadcast, the whole download is allocated
+/// up front
+const BROADCAST_MAX_SIZE: usize = 32 * 1024 * 1024;
+
+/// Reasons a TFTP operation can fail. The first 7 match the TFTP error codes
+/// a server can send, however the PXE stack only reports some of them
+/// distinctly, all others are reported as a `NetworkError`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TftpError {
+    /// TFTP error 1, the file does not exist on the server
+    FileNotFound,
+
+    /// TFTP error 2, the server denied access to the file
+    AccessViolation,
+
+    /// TFTP error 3, the file does not fit where it is being downloaded to
+    DiskFull,
+
+    /// TFTP error 4, the request was invalid, such as a filename which is too
+    /// long or an unsupported packet size
+    IllegalOperation,
+
+    /// TFTP error 5, a packet was received for an unknown transfer ID
+    UnknownTid,
+
+    /// TFTP error 6, the file already exists
+    FileExists,
+
+    /// TFTP error 7, no such user
+    NoSuchUser,
+
+    /// The server did not respond in time
+    Timeout,
+
+    /// Any other failure of the PXE stack or the network
+    NetworkError,
+}
+
+impl TftpError {
+    /// Convert a failing PXE API status from a TFTP call into an error
+    fn from_status(status: u16) -> TftpError {
+        const PXENV_STATUS_TFTP_OPEN_TIMEOUT:        u16 = 0x32;
+        const PXENV_STATUS_TFTP_UNKNOWN_OPCODE:      u16 = 0x33;
+        const PXENV_STATUS_TFTP_READ_TIMEOUT:        u16 = 0x35;
+        const PXENV_STATUS_TFTP_FILE_NOT_FOUND:      u16 = 0x3b;
+        const PXENV_STATUS_TFTP_ACCESS_VIOLATION:    u16 = 0x3c;
+        const PXENV_STATUS_TFTP_INVALID_PACKET_SIZE: u16 = 0x3f;
+
+        match status {
+            PXENV_STATUS_TFTP_OPEN_TIMEOUT |
+            PXENV_STATUS_TFTP_READ_TIMEOUT        => TftpError::Timeout,
+            PXENV_STATUS_TFTP_UNKNOWN_OPCODE |
+            PXENV_STATUS_TFTP_INVALID_PACKET_SIZE =>
+                TftpError::IllegalOperation,
+            PXENV_STATUS_TFTP_FILE_NOT_FOUND      => TftpError::FileNotFound,
+            PXENV_STATUS_TFTP_ACCESS_VIOLATION    =>
+                TftpError::AccessViolation,
+            _                                     => TftpError::NetworkError,
+        }
+    }
+}
+
+/// Get the contents of `filename` if it is embedded in the bootloader or was
+/// already received over UDP broadcast, in which case it is used instead of
+/// downloading the file over TFTP
+///
+/// With the `embedded-test-kernel` feature, `chocolate_milk.kern` is a
+/// minimal PE64 with a `.text` section containing a `cli; hlt` loop at the
+/// entry point, and a 4 KiB `.bss` section. It is generated by
+/// `embedded_test_kernel.py`.
+fn simulate_download(filename: &[u8]) -> Option<&'static [u8]> {
+    if filename != b"chocolate_milk.kern" {
+        return None;
+    }
+
+    #[cfg(feature = "embedded-test-kernel")]
+    {
+        return Some(include_bytes!("embedded_test_kernel.bin"));
+    }
+
+    #[cfg(not(feature = "embedded-test-kernel"))]
+    {
+        *BROADCAST_KERNEL.lock()
+    }
+}
+
 /// Convert a 16-bit `seg:off` pointer into a linear address
 fn segoff_to_linear(seg: u16, off: u16) -> usize {
     ((seg as usize) << 4) + off as usize
 }
 
-/// Download a file with the `filename` over TFTP with the PXE 16-bit API
-pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
-    // Lock access to PXE
-    let _guard = PXE_GUARD.lock();
-
-    // Convert the filename to a slice of bytes
-    let filename: &[u8] = filename.as_ref();
-
+/// Locate and validate the PXENV+ structure
+fn pxenv() -> Option<&'static [u8]> {
     // Invoke the PXE installation check with int 0x1a
     let mut regs = RegisterState::default();
     regs.eax = 0x5650;
@@ -34,40 +155,33 @@ pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
         return None;
     }
 
-    // Get the linear address to the PXENV+ structure
+    // Get and validate the PXENV+ structure
     let pxenv = segoff_to_linear(regs.es, regs.ebx as u16);
-    let pxenv = unsafe {
-        core::slice::from_raw_parts(pxenv as *const u8, 0x2c)
-    };
-
-    // Extract the fields we need to validate the PXENV+ structure
-    let signature = &pxenv[..6];
-    let length    = pxenv[0x8];
-    let checksum  = pxenv.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
-
-    // Check for correctness
-    if signature != b"PXENV+" || length != 0x2c || checksum != 0 {
-        return None;
+    match pxe_struct_at(pxenv)? {
+        PxeStructure::Pxenv(pxenv) => Some(pxenv),
+        PxeStructure::Pxe(_)       => None,
     }
+}
 
-    // Get the pointer to the !PXE structure
-    let off = u16::from_le_bytes(pxenv[0x28..0x2a].try_into().ok()?);
-    let seg = u16::from_le_bytes(pxenv[0x2a..0x2c].try_into().ok()?);
-    let pxe = segoff_to_linear(seg, off);
-    let pxe = unsafe {
-        core::slice::from_raw_parts(pxe as *const u8, 0x58)
+/// Locate the PXE API and return the 16-bit real-mode entry point for it as a
+/// `(seg, off)` tuple
+fn entry_point() -> Option<(u16, u16)> {
+    // Find the PXE structures with the installation check, falling back to
+    // scanning memory for them
+    let pxe = match pxenv().map(PxeStructure::Pxenv)
+            .or_else(get_pxe_struct_address)? {
+        PxeStructure::Pxe(pxe)     => pxe,
+        PxeStructure::Pxenv(pxenv) => {
+            // Get and validate the !PXE structure this links to
+            let off = u16::from_le_bytes(pxenv[0x28..0x2a].try_into().ok()?);
+            let seg = u16::from_le_bytes(pxenv[0x2a..0x2c].try_into().ok()?);
+            match pxe_struct_at(segoff_to_linear(seg, off))? {
+                PxeStructure::Pxe(pxe) => pxe,
+                PxeStructure::Pxenv(_) => return None,
+            }
+        }
     };
 
-    // Extract the fields we need to validate the !PXE structure
-    let signature = &pxe[..4];
-    let length    = pxe[4];
-    let checksum  = pxe.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
-
-    // Check for correctness
-    if signature != b"!PXE" || length != 0x58 || checksum != 0 {
-        return None;
-    }
-
     // Get the 16-bit PXE API entry point
     let ep_off = u16::from_le_bytes(pxe[0x10..0x12].try_into().ok()?);
     let ep_seg = u16::from_le_bytes(pxe[0x12..0x14].try_into().ok()?);
@@ -77,145 +191,610 @@ pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
         return None;
     }
 
-    // Determine the server IP from the cached information used during the PXE
-    // boot process. We grab the DHCP ACK packet and extract the server IP
-    // field from it.
-    let server_ip: [u8; 4] = {
-        const PXE_OPCODE_GET_CACHED_INFO: u16 = 0x71;
-        const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
+    Some((ep_seg, ep_off))
+}
 
-        #[derive(Default)]
-        #[repr(C)]
-        struct GetCachedInfo {
-            status:       u16,
-            packet_type:  u16,
-            buffer_size:  u16,
-            buffer_off:   u16,
-            buffer_seg:   u16,
-            buffer_limit: u16,
-        }
+/// Get the DHCP ACK packet which was cached by the PXE stack during the PXE
+/// boot process
+fn dhcp_ack(ep_seg: u16, ep_off: u16) -> Option<[u8; 1024]> {
+    const PXE_OPCODE_GET_CACHED_INFO: u16 = 0x71;
+    const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
 
-        // Buffer to hold the DHCP ACK packet
-        let mut pkt_buf = [0u8; 128];
+    #[derive(Default)]
+    #[repr(C)]
+    struct GetCachedInfo {
+        status:       u16,
+        packet_type:  u16,
+        buffer_size:  u16,
+        buffer_off:   u16,
+        buffer_seg:   u16,
+        buffer_limit: u16,
+    }
 
-        // Request the DHCP ACK packet
-        let mut st = GetCachedInfo::default();
-        st.packet_type = PXENV_PACKET_TYPE_DHCP_ACK;
-        st.buffer_size = pkt_buf.len() as u16;
-        st.buffer_seg  = 0;
-        st.buffer_off  = &mut pkt_buf as *mut _ as u16;
-        unsafe {
-            pxecall(ep_seg, ep_off, PXE_OPCODE_GET_CACHED_INFO,
-                0, &mut st as *mut _ as u16);
+    // Buffer to hold the DHCP ACK packet, this is large enough to hold the
+    // DHCP options as well as the BOOTP header
+    let mut pkt_buf = [0u8; 1024];
+
+    // Request the DHCP ACK packet
+    let mut st = GetCachedInfo::default();
+    st.packet_type = PXENV_PACKET_TYPE_DHCP_ACK;
+    st.buffer_size = pkt_buf.len() as u16;
+    st.buffer_seg  = 0;
+    st.buffer_off  = &mut pkt_buf as *mut _ as u16;
+    unsafe {
+        pxecall(ep_seg, ep_off, PXE_OPCODE_GET_CACHED_INFO,
+            0, &mut st as *mut _ as u16);
+    }
+
+    // Make sure this call was successful
+    if st.status != 0 {
+        return None;
+    }
+
+    Some(pkt_buf)
+}
+
+/// Find the DHCP option `code` in a DHCP `packet` and return its contents
+fn dhcp_option(packet: &[u8], code: u8) -> Option<&[u8]> {
+    // Make sure the DHCP magic cookie is present after the BOOTP header
+    if packet.get(236..240)? != &[0x63, 0x82, 0x53, 0x63] {
+        return None;
+    }
+
+    // Walk the options
+    let mut ptr = 240;
+    loop {
+        match *packet.get(ptr)? {
+            // Pad option, which has no length
+            0 => ptr += 1,
+
+            // End option
+            255 => return None,
+
+            opt => {
+                let len  = *packet.get(ptr + 1)? as usize;
+                let data = packet.get(ptr + 2..ptr + 2 + len)?;
+                if opt == code {
+                    return Some(data);
+                }
+                ptr += 2 + len;
+            }
         }
+    }
+}
 
-        // Make sure this call was successful
-        if st.status != 0 {
-            return None;
+/// Parse a dotted-decimal IPv4 address, eg. `192.168.1.1`. A trailing null
+/// terminator is allowed.
+fn parse_ipv4(string: &[u8]) -> Option<[u8; 4]> {
+    let string = core::str::from_utf8(string).ok()?.trim_end_matches('\0');
+
+    let mut ip = [0u8; 4];
+    let mut octets = string.split('.');
+    for octet in ip.iter_mut() {
+        *octet = octets.next()?.parse().ok()?;
+    }
+
+    // Make sure there were exactly 4 octets
+    if octets.next().is_some() {
+        return None;
+    }
+
+    Some(ip)
+}
+
+/// Determine the TFTP server IP address. An override from `set_next_server`
+/// is always used if present. Otherwise, from the DHCP ACK, the Cisco TFTP
+/// server option 150 is preferred, then the TFTP server name option 66 if it
+/// is an IP address, and finally the `siaddr` field of the BOOTP header.
+fn server_ip_int(ep_seg: u16, ep_off: u16) -> Option<[u8; 4]> {
+    if let Some(ip) = *NEXT_SERVER.lock() {
+        return Some(ip);
+    }
+
+    let ack = dhcp_ack(ep_seg, ep_off)?;
+
+    // Option 150, a list of TFTP server IPs of which we use the first
+    if let Some(servers) = dhcp_option(&ack, 150) {
+        if let Some(ip) = servers.get(..4) {
+            return ip.try_into().ok();
         }
+    }
+
+    // Option 66, the TFTP server name
+    if let Some(ip) = dhcp_option(&ack, 66).and_then(parse_ipv4) {
+        return Some(ip);
+    }
+
+    // Fall back to the `siaddr` field in the BOOTP header
+    ack[0x14..0x18].try_into().ok()
+}
+
+/// Get the IP address of the TFTP server which will be used for downloads
+pub fn server_ip() -> Option<[u8; 4]> {
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    let (ep_seg, ep_off) = entry_point()?;
+    server_ip_int(ep_seg, ep_off)
+}
+
+/// Use `ip` as the TFTP server for all subsequent TFTP operations, rather
+/// than the server provided by DHCP
+pub fn set_next_server(ip: [u8; 4]) {
+    *NEXT_SERVER.lock() = Some(ip);
+}
+
+/// Get the value of the last `key=value` line for `key` in `cfg` which
+/// `parse` accepts, ignoring surrounding whitespace
+fn cfg_value<T>(cfg: &[u8], key: &str, parse: impl Fn(&str) -> Option<T>)
+        -> Option<T> {
+    cfg.split(|&x| x == b'\n')
+        .filter_map(|line| core::str::from_utf8(line).ok())
+        .filter_map(|line| {
+            let (name, val) = line.split_at(line.find('=')?);
+            if name.trim() == key {
+                parse(val[1..].trim())
+            } else {
+                None
+            }
+        })
+        .last()
+}
+
+/// Apply the settings from `boot.cfg` on the TFTP server, if it has one.
+/// A `tftp_server=A.B.C.D` line makes us use that server for all subsequent
+/// TFTP operations, and a `reboot_delay_secs=N` line sets
+/// `BOOT_ARGS.reboot_delay_secs`.
+///
+/// A `broadcast_port=N` line along with a `broadcast_magic=0xN` line makes us
+/// receive `chocolate_milk.kern` with `download_udp_broadcast` rather than
+/// over TFTP. It is received here, and then handed out like an embedded file.
+/// If it can't be received, it is downloaded over TFTP as usual.
+pub fn load_boot_cfg() {
+    if !exists("boot.cfg") {
+        return;
+    }
 
-        // Extract the server IP
-        pkt_buf[0x14..0x18].try_into().ok()?
+    let cfg = match download("boot.cfg") {
+        Ok(cfg) => cfg,
+        Err(_)  => return,
     };
 
-    print!("TFTP Server IP: {}.{}.{}.{}\n",
-                   server_ip[0], server_ip[1], server_ip[2], server_ip[3]);
+    if let Some(server) = cfg_value(&cfg, "tftp_server",
+                                    |val| parse_ipv4(val.as_bytes())) {
+        set_next_server(server);
+    }
 
-    // Get the file size for the next stage
-    let file_size = {
-        const PXE_OPCODE_TFTP_GET_FILE_SIZE: u16 = 0x25;
-
-        #[repr(C, packed)]
-        struct GetFileSize {
-            status:     u16,
-            server_ip:  [u8; 4],
-            gateway_ip: [u8; 4],
-            filename:   [u8; 128],
-            file_size:  u32,
+    if let Some(delay) = cfg_value(&cfg, "reboot_delay_secs",
+                                   |val| val.parse().ok()) {
+        crate::BOOT_ARGS.reboot_delay_secs.store(delay,
+            core::sync::atomic::Ordering::SeqCst);
+    }
+
+    let port  = cfg_value(&cfg, "broadcast_port", |val| val.parse().ok());
+    let magic = cfg_value(&cfg, "broadcast_magic", |val| {
+        u32::from_str_radix(val.trim_start_matches("0x"), 16).ok()
+    });
+    if let (Some(port), Some(magic)) = (port, magic) {
+        match download_udp_broadcast(port, magic, BROADCAST_IDLE_TIMEOUT_MS) {
+            Some(kernel) => {
+                // The kernel is handed out for the rest of the boot, hold
+                // onto it for good
+                *BROADCAST_KERNEL.lock() =
+                    Some(Box::leak(kernel.into_boxed_slice()));
+            }
+            None => {
+                print!("Failed to receive chocolate_milk.kern over UDP, \
+                        falling back to TFTP\n");
+            }
         }
+    }
+}
 
-        // Create the file size request
-        let mut st = GetFileSize {
-            status:     0,
-            server_ip:  server_ip, 
-            gateway_ip: [0; 4],
-            filename:   [0; 128],
-            file_size:  0,
-        };
+/// Open the file `filename` on the TFTP server `server_ip` for reading. The
+/// PXE stack sends the read request and waits for the server's response,
+/// thus this fails if the file does not exist.
+fn tftp_open(ep_seg: u16, ep_off: u16, server_ip: [u8; 4], filename: &[u8])
+        -> Result<(), TftpError> {
+    const PXE_OPCODE_TFTP_OPEN: u16 = 0x20;
 
-        // Check to see if we have enough room for the filename and null
-        // terminator
-        if filename.len() + 1 > st.filename.len() {
-            return None;
-        }
+    #[repr(C)]
+    struct TftpOpen {
+        status:      u16,
+        server_ip:   [u8; 4],
+        gateway_ip:  [u8; 4],
+        filename:    [u8; 128],
+        tftp_port:   u16,
+        packet_size: u16,
+    }
 
-        // Copy in the file name
-        st.filename[..filename.len()].copy_from_slice(filename);
+    // Create the file open request. We only hand the PXE stack IP addresses,
+    // it performs the ARP resolution of the server (or the gateway used to
+    // reach it) itself.
+    let mut st = TftpOpen {
+        status:      0,
+        server_ip:   server_ip, 
+        gateway_ip:  [0; 4],
+        filename:    [0; 128],
+        tftp_port:   69u16.to_be(),
+        packet_size: 512,
+    };
 
-        // Do the request
-        unsafe {
-            pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_GET_FILE_SIZE,
-                0, &mut st as *mut _ as u16);
-        }
+    // Check to see if we have enough room for the filename and null
+    // terminator
+    if filename.len() + 1 > st.filename.len() {
+        return Err(TftpError::IllegalOperation);
+    }
 
-        // Check that the call was successful
-        if st.status != 0 {
-            return None;
-        }
+    // Copy in the file name
+    st.filename[..filename.len()].copy_from_slice(filename);
+    
+    // Do the request
+    unsafe {
+        pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_OPEN,
+            0, &mut st as *mut _ as u16);
+    }
+
+    // Check that the call was successful. We require 512 byte packets, which
+    // always fit in a single Ethernet frame, thus TFTP data is never IP
+    // fragmented.
+    if st.status != 0 {
+        return Err(TftpError::from_status(st.status));
+    }
+    if st.packet_size != 512 {
+        return Err(TftpError::IllegalOperation);
+    }
+
+    Ok(())
+}
+
+/// Close the currently open TFTP file. If the transfer has not completed,
+/// the PXE stack aborts it by sending the server an error packet.
+fn tftp_close(ep_seg: u16, ep_off: u16) -> Option<()> {
+    const PXE_OPCODE_TFTP_CLOSE: u16 = 0x21;
+
+    // Create a status for returning
+    let mut status: u16 = 0;
+    
+    // Do the request
+    unsafe {
+        pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_CLOSE,
+            0, &mut status as *mut _ as u16);
+    }
+    
+    // Check that the call was successful
+    if status != 0 {
+        return None;
+    }
+
+    Some(())
+}
+
+/// Check if the file `filename` exists on the TFTP server without
+/// downloading it. The transfer is opened, which fails if the server responds
+/// with a TFTP error (such as error 1, file not found), and then immediately
+/// aborted, thus only a single round trip is paid.
+pub fn exists(filename: &str) -> bool {
+    if simulate_download(filename.as_bytes()).is_some() {
+        return true;
+    }
+
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    (|| {
+        let (ep_seg, ep_off) = entry_point()?;
+        let server_ip = server_ip_int(ep_seg, ep_off)?;
+
+        // Open the file, and abort the transfer again if it opened
+        tftp_open(ep_seg, ep_off, server_ip, filename.as_bytes()).ok()?;
+        tftp_close(ep_seg, ep_off)
+    })().is_some()
+}
+
+/// Get the network configuration the PXE firmware obtained over DHCP
+pub fn server_info() -> Option<PxeServerInfo> {
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    let (ep_seg, ep_off) = entry_point()?;
+    let ack = dhcp_ack(ep_seg, ep_off)?;
+
+    // Get the subnet mask from option 1
+    let subnet_mask = dhcp_option(&ack, 1)
+        .and_then(|x| x.get(..4)?.try_into().ok())
+        .unwrap_or([0; 4]);
+
+    // Use the first router from option 3 as the gateway, otherwise fall back
+    // to the relay agent address `giaddr` in the BOOTP header
+    let gateway_ip = dhcp_option(&ack, 3)
+        .and_then(|x| x.get(..4)?.try_into().ok())
+        .unwrap_or(ack[0x18..0x1c].try_into().ok()?);
 
-        st.file_size as usize
+    // Get the server host name `sname` from the BOOTP header
+    let mut server_hostname = [0u8; 64];
+    server_hostname.copy_from_slice(&ack[0x2c..0x6c]);
+
+    Some(PxeServerInfo {
+        server_ip:       server_ip_int(ep_seg, ep_off)?,
+        client_ip:       ack[0x10..0x14].try_into().ok()?,
+        gateway_ip:      gateway_ip,
+        subnet_mask:     subnet_mask,
+        server_hostname: server_hostname,
+    })
+}
+
+/// Get the 802.1Q VLAN ID the DHCP server gave us in option 132, if any.
+/// Tagging cannot be applied to the frames the PXE stack builds, see the
+/// module documentation.
+pub fn vlan_id() -> Option<u16> {
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    let (ep_seg, ep_off) = entry_point()?;
+    let ack = dhcp_ack(ep_seg, ep_off)?;
+
+    // The VLAN ID is the low 12 bits of a big-endian integer, 0 and 4095 are
+    // reserved and mean no VLAN
+    let option = dhcp_option(&ack, 132)?;
+    let vlan = option.iter().fold(0u32, |acc, &x| (acc << 8) | x as u32);
+    let vlan = (vlan & 0xfff) as u16;
+    if vlan == 0 || vlan == 0xfff {
+        return None;
+    }
+
+    Some(vlan)
+}
+
+/// Get the current MAC address of the NIC from the UNDI driver
+fn nic_mac_int(ep_seg: u16, ep_off: u16) -> Option<[u8; 6]> {
+    const PXE_OPCODE_UNDI_GET_INFORMATION: u16 = 0x0c;
+
+    #[derive(Default)]
+    #[repr(C)]
+    struct UndiGetInformation {
+        status:        u16,
+        base_io:       u16,
+        int_number:    u16,
+        max_tran_unit: u16,
+        hw_type:       u16,
+        hw_addr_len:   u16,
+        current_addr:  [u8; 16],
+        perm_addr:     [u8; 16],
+        rom_address:   u16,
+        rx_buf_ct:     u16,
+        tx_buf_ct:     u16,
+    }
+
+    let mut st = UndiGetInformation::default();
+    let status = unsafe {
+        pxe_api_call(ep_seg, ep_off, PXE_OPCODE_UNDI_GET_INFORMATION,
+                     &mut st as *mut _ as *mut u8)
     };
 
-    print!("Requested file \"{}\" is {} bytes\n",
-        core::str::from_utf8(filename).ok()?, file_size);
+    // Make sure the call was successful and this is an Ethernet address
+    if status != 0 || st.hw_addr_len != 6 {
+        return None;
+    }
 
-    // Open the file
-    {
-        const PXE_OPCODE_TFTP_OPEN: u16 = 0x20;
+    st.current_addr[..6].try_into().ok()
+}
 
-        #[repr(C)]
-        struct TftpOpen {
-            status:      u16,
-            server_ip:   [u8; 4],
-            gateway_ip:  [u8; 4],
-            filename:    [u8; 128],
-            tftp_port:   u16,
-            packet_size: u16,
-        }
-        
-        // Create the file open request
-        let mut st = TftpOpen {
-            status:      0,
-            server_ip:   server_ip, 
-            gateway_ip:  [0; 4],
-            filename:    [0; 128],
-            tftp_port:   69u16.to_be(),
-            packet_size: 512,
-        };
+/// Get the network configuration along with the location of the UNDI network
+/// driver, such that the kernel can bring up networking without DHCP
+pub fn network_info() -> Option<PxeNetworkInfo> {
+    let server = server_info()?;
 
-        // Check to see if we have enough room for the filename and null
-        // terminator
-        if filename.len() + 1 > st.filename.len() {
-            return None;
-        }
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
 
-        // Copy in the file name
-        st.filename[..filename.len()].copy_from_slice(filename);
-        
-        // Do the request
-        unsafe {
-            pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_OPEN,
-                0, &mut st as *mut _ as u16);
-        }
+    // Get the client MAC address from the UNDI driver, falling back to
+    // `chaddr` in the BOOTP header
+    let (ep_seg, ep_off) = entry_point()?;
+    let client_mac = match nic_mac_int(ep_seg, ep_off) {
+        Some(mac) => mac,
+        None      => dhcp_ack(ep_seg, ep_off)?[0x1c..0x22].try_into().ok()?,
+    };
 
-        // Check that the call was successful
-        if st.status != 0 || st.packet_size != 512 {
-            return None;
-        }
+    // Get the UNDI code segment from the PXENV+ structure
+    let pxenv = pxenv()?;
+    let undi_seg  = u16::from_le_bytes(pxenv[0x24..0x26].try_into().ok()?);
+    let undi_size = u16::from_le_bytes(pxenv[0x26..0x28].try_into().ok()?);
+
+    Some(PxeNetworkInfo {
+        client_mac:    client_mac,
+        client_ip:     server.client_ip,
+        server_ip:     server.server_ip,
+        gateway:       server.gateway_ip,
+        subnet_mask:   server.subnet_mask,
+        pxe_undi_base: segoff_to_linear(undi_seg, 0) as u32,
+        pxe_undi_size: undi_size as u32,
+    })
+}
+
+/// Get the size of the file `filename` on the TFTP server `server_ip`
+fn tftp_file_size(ep_seg: u16, ep_off: u16, server_ip: [u8; 4],
+                  filename: &[u8]) -> Result<usize, TftpError> {
+    const PXE_OPCODE_TFTP_GET_FILE_SIZE: u16 = 0x25;
+
+    #[repr(C, packed)]
+    struct GetFileSize {
+        status:     u16,
+        server_ip:  [u8; 4],
+        gateway_ip: [u8; 4],
+        filename:   [u8; 128],
+        file_size:  u32,
+    }
+
+    // Create the file size request
+    let mut st = GetFileSize {
+        status:     0,
+        server_ip:  server_ip, 
+        gateway_ip: [0; 4],
+        filename:   [0; 128],
+        file_size:  0,
+    };
+
+    // Check to see if we have enough room for the filename and null
+    // terminator
+    if filename.len() + 1 > st.filename.len() {
+        return Err(TftpError::IllegalOperation);
+    }
+
+    // Copy in the file name
+    st.filename[..filename.len()].copy_from_slice(filename);
+
+    // Do the request
+    unsafe {
+        pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_GET_FILE_SIZE,
+            0, &mut st as *mut _ as u16);
+    }
+
+    // Check that the call was successful
+    if st.status != 0 {
+        return Err(TftpError::from_status(st.status));
     }
 
-    // Read the file
-    let mut download = Vec::with_capacity(file_size);
+    Ok(st.file_size as usize)
+}
+
+/// Get the size of the file `filename` on the TFTP server in bytes
+pub fn file_size(filename: &str) -> Result<u64, TftpError> {
+    if let Some(file) = simulate_download(filename.as_bytes()) {
+        return Ok(file.len() as u64);
+    }
+
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    let (ep_seg, ep_off) = entry_point().ok_or(TftpError::NetworkError)?;
+    let server_ip = server_ip_int(ep_seg, ep_off)
+        .ok_or(TftpError::NetworkError)?;
+    tftp_file_size(ep_seg, ep_off, server_ip, filename.as_bytes())
+        .map(|x| x as u64)
+}
+
+/// Download a file with the `filename` over TFTP with the PXE 16-bit API
+pub fn download<P: AsRef<[u8]>>(filename: P) -> Result<Vec<u8>, TftpError> {
+    download_int(filename.as_ref(),
+        |file_size| Some(Vec::with_capacity(file_size)),
+        |download, data| {
+            // Make sure we don't overflow our allocation. This can happen if
+            // the file has changed since we got the size. We'll just fail
+            // here rather than causing re-allocs which are not handled well
+            // with our high-fragmentation bootloader heap.
+            if download.len() + data.len() > download.capacity() {
+                return None;
+            }
+
+            // Record the downloaded bytes
+            download.extend_from_slice(data);
+            Some(())
+        })
+}
+
+/// Download a file with the `filename` over TFTP with the PXE 16-bit API
+/// directly into physical memory at `paddr`, such that it never has to be
+/// held in the heap. At most `max_size` bytes will be written, the download
+/// fails if the file is larger.
+///
+/// Returns the number of bytes written.
+pub fn download_to_phys(filename: &str, paddr: u64, max_size: u64)
+        -> Result<u64, TftpError> {
+    download_int(filename.as_bytes(),
+        |file_size| {
+            // Make sure the file fits
+            if file_size as u64 > max_size {
+                return None;
+            }
+
+            Some(0u64)
+        },
+        |written, data| {
+            // Make sure we don't write past the end of the buffer, as the
+            // file may have changed since we got the size
+            let new_written = written.checked_add(data.len() as u64)?;
+            if new_written > max_size {
+                return None;
+            }
+
+            // Write the data into physical memory, which is identity mapped
+            unsafe {
+                core::ptr::copy_nonoverlapping(data.as_ptr(),
+                    (paddr + *written) as usize as *mut u8, data.len());
+            }
+
+            *written = new_written;
+            Some(())
+        })
+}
+
+/// Same as `download_to_phys`, additionally returning the number of
+/// nanoseconds the download took
+pub fn download_to_phys_timed(filename: &str, paddr: u64, max_size: u64)
+        -> Result<(u64, u64), TftpError> {
+    // Make sure the TSC is calibrated before we start timing
+    crate::time::tsc_hz();
+
+    let start = cpu::rdtsc();
+    let written = download_to_phys(filename, paddr, max_size)?;
+    let elapsed = crate::time::tsc_to_ns(cpu::rdtsc() - start);
+
+    Ok((written, elapsed))
+}
+
+/// Download a file with the `filename` over TFTP with the PXE 16-bit API.
+/// `start` is invoked with the size of the file to create the download state,
+/// and `write` is invoked with the state and every chunk of the file in order.
+/// If either fails, the file doesn't fit and `TftpError::DiskFull` is
+/// returned.
+fn download_int<T>(filename: &[u8],
+                   start: impl FnOnce(usize) -> Option<T>,
+                   mut write: impl FnMut(&mut T, &[u8]) -> Option<()>)
+        -> Result<T, TftpError> {
+    // Hand out embedded files as if they were a single TFTP packet
+    if let Some(file) = simulate_download(filename) {
+        let mut download = start(file.len()).ok_or(TftpError::DiskFull)?;
+        write(&mut download, file).ok_or(TftpError::DiskFull)?;
+        return Ok(download);
+    }
+
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    // Get the PXE API entry point
+    let (ep_seg, ep_off) = entry_point().ok_or(TftpError::NetworkError)?;
+
+    // Determine the TFTP server IP to download from
+    let server_ip = server_ip_int(ep_seg, ep_off)
+        .ok_or(TftpError::NetworkError)?;
+
+    print!("TFTP Server IP: {}.{}.{}.{}\n",
+                   server_ip[0], server_ip[1], server_ip[2], server_ip[3]);
+
+    // Get the file size for the next stage
+    let file_size = tftp_file_size(ep_seg, ep_off, server_ip, filename)?;
+
+    print!("Requested file \"{}\" is {} bytes\n",
+        core::str::from_utf8(filename).unwrap_or("?"), file_size);
+
+    // Set up the download
+    let mut download = start(file_size).ok_or(TftpError::DiskFull)?;
+
+    // The PXE stack only times out reads which get no packets at all, thus a
+    // server which keeps sending just often enough could stall us forever.
+    // Give up on the download once it falls behind the minimum rate. The
+    // deadline can only be checked between reads, as they block.
+    let timeout = TFTP_TIMEOUT_SECS + file_size as u64 / TFTP_MIN_BYTES_PER_SEC;
+    let deadline = cpu::rdtsc()
+        .saturating_add(timeout.saturating_mul(crate::time::tsc_hz()));
+
+    // Open the file
+    tftp_open(ep_seg, ep_off, server_ip, filename)?;
+
+    // Read the file. The PXE stack handles the UDP layer (including checksum
+    // validation) and TFTP acknowledgements for us, we only ever get handed
+    // the payloads of in-order TFTP DATA packets.
     loop {
         const PXE_OPCODE_TFTP_READ: u16 = 0x22;
 
@@ -241,7 +820,13 @@ pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
             buffer_seg:    0,
         };
         
-        // Do the request
+        // Do the request. TFTP_READ blocks in the PXE stack until a packet
+        // arrives, and there is no way for us to abort it from the outside,
+        // the PXE stack is not reentrant and the BIOS owns the IVT while we're
+        // in real mode. Thus we rely on the PXE stack's own retransmissions
+        // and timeout, which it reports as `PXENV_STATUS_TFTP_READ_TIMEOUT`,
+        // for a single read. A PXE stack which never times out can still
+        // hang us here.
         unsafe {
             pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_READ,
                 0, &mut st as *mut _ as u16);
@@ -250,48 +835,251 @@ pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
         // Get the number of bytes read
         let bread = st.bytes_read as usize;
         
-        // Check that the call was successful
-        if st.status != 0 || bread > read_buf.len() {
-            return None;
+        // Check that the call was successful, letting the kernel know if the
+        // server stopped responding
+        if st.status != 0 {
+            let err = TftpError::from_status(st.status);
+            if err == TftpError::Timeout {
+                crate::BOOT_ARGS.pxe_timeout.store(true,
+                    core::sync::atomic::Ordering::SeqCst);
+            }
+            return Err(err);
         }
-
-        // Make sure we don't overflow our allocation. This can happen if the
-        // file has changed since we got the size. We'll just fail here rather
-        // than causing re-allocs which are not handled well with our
-        // high-fragmentation bootloader heap.
-        if download.len() + bread > download.capacity() {
-            return None;
+        if bread > read_buf.len() {
+            return Err(TftpError::NetworkError);
         }
 
         // Record the downloaded bytes
-        download.extend_from_slice(&read_buf[..bread]);
+        write(&mut download, &read_buf[..bread]).ok_or(TftpError::DiskFull)?;
+
+        // Keep the hardware watchdog from firing during long downloads
+        if let Some(watchdog) = crate::BOOT_ARGS.watchdog.lock().as_ref() {
+            watchdog.pet();
+        }
 
         // Check to see if this was the final packet, indicated by a partial
         // packet
         if bread < read_buf.len() {
             break;
         }
+
+        // Give up if the server is too slow
+        if cpu::rdtsc() >= deadline {
+            crate::BOOT_ARGS.pxe_timeout.store(true,
+                core::sync::atomic::Ordering::SeqCst);
+            let _ = tftp_close(ep_seg, ep_off);
+            return Err(TftpError::Timeout);
+        }
     }
 
     // Close file
-    {
-        const PXE_OPCODE_TFTP_CLOSE: u16 = 0x21;
+    tftp_close(ep_seg, ep_off).ok_or(TftpError::NetworkError)?;
+
+    Ok(download)
+}
+
+/// Open the PXE UDP stack, using the IP address from DHCP as the source
+fn udp_open(ep_seg: u16, ep_off: u16) -> Option<()> {
+    const PXE_OPCODE_UDP_OPEN: u16 = 0x30;
+
+    #[repr(C)]
+    struct UdpOpen {
+        status: u16,
+        src_ip: [u8; 4],
+    }
+
+    let mut st = UdpOpen {
+        status: 0,
+        src_ip: [0; 4],
+    };
+
+    unsafe {
+        pxecall(ep_seg, ep_off, PXE_OPCODE_UDP_OPEN,
+            0, &mut st as *mut _ as u16);
+    }
+
+    if st.status != 0 {
+        return None;
+    }
+
+    Some(())
+}
+
+/// Close the PXE UDP stack
+fn udp_close(ep_seg: u16, ep_off: u16) -> Option<()> {
+    const PXE_OPCODE_UDP_CLOSE: u16 = 0x31;
+
+    let mut status: u16 = 0;
+
+    unsafe {
+        pxecall(ep_seg, ep_off, PXE_OPCODE_UDP_CLOSE,
+            0, &mut status as *mut _ as u16);
+    }
+
+    if status != 0 {
+        return None;
+    }
+
+    Some(())
+}
+
+/// Download a file which is broadcast over UDP to `port`, rather than
+/// fetching it over TFTP. Every packet of the file starts with the big-endian
+/// `magic` and a big-endian 32-bit sequence number, followed by the data.
+/// Packets may arrive in any order and repeatedly, the data is reassembled in
+/// sequence number order starting at sequence number 0.
+///
+/// Like TFTP, every packet carries `BROADCAST_CHUNK_SIZE` bytes of data except
+/// for the final one, which is shorter (and possibly empty). This lets every
+/// chunk go straight to its place in a single buffer, which is allocated up
+/// front for a file of up to `BROADCAST_MAX_SIZE` bytes, rather than growing
+/// the download in our high-fragmentation bootloader heap.
+///
+/// The download ends once every chunk up to the final one has been received,
+/// or once no packet has been received for `timeout_ms` milliseconds. Returns
+/// `None` if the file is incomplete, or if it is too large.
+pub fn download_udp_broadcast(port: u16, magic: u32, timeout_ms: u32)
+        -> Option<Vec<u8>> {
+    const PXE_OPCODE_UDP_READ: u16 = 0x32;
+
+    /// Maximum number of chunks, and thus bits in the received bitmap
+    const MAX_CHUNKS: usize =
+        (BROADCAST_MAX_SIZE + BROADCAST_CHUNK_SIZE - 1) / BROADCAST_CHUNK_SIZE;
+
+    #[repr(C)]
+    struct UdpRead {
+        status:      u16,
+        src_ip:      [u8; 4],
+        dest_ip:     [u8; 4],
+        s_port:      u16,
+        d_port:      u16,
+        buffer_size: u16,
+        buffer_off:  u16,
+        buffer_seg:  u16,
+    }
+
+    // Allocate the whole download and a bitmap of the received chunks before
+    // we start, such that nothing is allocated per packet
+    let mut download = vec![0u8; BROADCAST_MAX_SIZE];
+    let mut received = vec![0u64; (MAX_CHUNKS + 63) / 64];
+    let mut num_received = 0usize;
+
+    // Sequence number and size of the final, partial, chunk once we know it,
+    // and the highest sequence number received
+    let mut last: Option<(usize, usize)> = None;
+    let mut highest = 0usize;
+
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    // Get the PXE API entry point
+    let (ep_seg, ep_off) = entry_point()?;
+
+    udp_open(ep_seg, ep_off)?;
+
+    // UDP reads don't block, poll for packets until we have the whole file or
+    // the network has been idle for the timeout
+    let mut valid = true;
+    crate::apic::start_one_shot_timer(timeout_ms.saturating_mul(1000));
+    while !crate::apic::timer_expired() &&
+            last.map(|(seq, _)| num_received != seq + 1).unwrap_or(true) {
+        // Room for the header and a full chunk, the largest UDP payload of an
+        // unfragmented Ethernet frame
+        let mut read_buf = [0u8; 8 + BROADCAST_CHUNK_SIZE];
+
+        // Accept packets sent to any address, such as broadcasts, as long as
+        // they are for our port
+        let mut st = UdpRead {
+            status:      0,
+            src_ip:      [0; 4],
+            dest_ip:     [0; 4],
+            s_port:      0,
+            d_port:      port.to_be(),
+            buffer_size: read_buf.len() as u16,
+            buffer_off:  &mut read_buf as *mut _ as u16,
+            buffer_seg:  0,
+        };
 
-        // Create a status for returning
-        let mut status: u16 = 0;
-        
-        // Do the request
         unsafe {
-            pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_CLOSE,
-                0, &mut status as *mut _ as u16);
+            pxecall(ep_seg, ep_off, PXE_OPCODE_UDP_READ,
+                0, &mut st as *mut _ as u16);
         }
-        
-        // Check that the call was successful
-        if status != 0 {
-            return None;
+
+        // Nothing was received yet, poll again until the idle timeout
+        if st.status != 0 {
+            cpu::pause();
+            continue;
+        }
+
+        // Ignore packets which are not part of this transfer
+        let packet = &read_buf[..core::cmp::min(st.buffer_size as usize,
+                                                read_buf.len())];
+        if packet.len() < 8 ||
+                u32::from_be_bytes(packet[..4].try_into().ok()?) != magic {
+            continue;
+        }
+        let seq  = u32::from_be_bytes(packet[4..8].try_into().ok()?) as usize;
+        let data = &packet[8..];
+
+        // A partial chunk ends the file
+        let partial = data.len() < BROADCAST_CHUNK_SIZE;
+        if partial && last.is_none() {
+            last = Some((seq, data.len()));
         }
+        highest = core::cmp::max(highest, seq);
+
+        // Give up on files which have multiple ends or data after their end,
+        // and on files which don't fit in the download
+        let bad_end = last.map(|(last, size)| {
+            highest > last ||
+                if partial { (seq, data.len()) != (last, size) }
+                else       { seq == last }
+        }).unwrap_or(false);
+        let offset = seq.saturating_mul(BROADCAST_CHUNK_SIZE);
+        if bad_end || seq >= MAX_CHUNKS ||
+                offset + data.len() > download.len() {
+            valid = false;
+            break;
+        }
+
+        // Save the data if we haven't seen this sequence number before
+        if received[seq / 64] & (1 << (seq % 64)) == 0 {
+            received[seq / 64] |= 1 << (seq % 64);
+            num_received += 1;
+            download[offset..offset + data.len()].copy_from_slice(data);
+        }
+
+        // Keep the hardware watchdog from firing during long downloads
+        if let Some(watchdog) = crate::BOOT_ARGS.watchdog.lock().as_ref() {
+            watchdog.pet();
+        }
+
+        // Restart the idle timeout
+        crate::apic::start_one_shot_timer(timeout_ms.saturating_mul(1000));
     }
+    crate::apic::stop_timer();
 
+    udp_close(ep_seg, ep_off)?;
+
+    if !valid {
+        return None;
+    }
+
+    // The idle timeout always ends an incomplete download, it's only a PXE
+    // timeout if nothing was received at all
+    if num_received == 0 {
+        crate::BOOT_ARGS.pxe_timeout.store(true,
+            core::sync::atomic::Ordering::SeqCst);
+        return None;
+    }
+
+    // Every chunk up to and including the final one must have arrived. No
+    // chunk past the final one is accepted, thus the count is enough.
+    let (last, last_size) = last?;
+    if num_received != last + 1 {
+        return None;
+    }
+
+    download.truncate(last * BROADCAST_CHUNK_SIZE + last_size);
     Some(download)
 }
-
@@ -24,3 +24,78 @@ extern {
                    param_seg: u16, param_off: u16);
 }
 
+/// Invoke the PXE API function `func_code` at the 16-bit entry point
+/// `ep_seg:ep_off` with the parameter structure `param`, returning the status
+/// from the first word of the parameter structure. Every PXE API parameter
+/// structure starts with the 16-bit status.
+///
+/// `param` must be below 1 MiB such that it is addressable from real mode.
+pub unsafe fn pxe_api_call(ep_seg: u16, ep_off: u16, func_code: u16,
+                           param: *mut u8) -> u16 {
+    let param = param as usize;
+    assert!(param < 1024 * 1024, "PXE parameter not addressable in real mode");
+
+    pxecall(ep_seg, ep_off, func_code,
+            (param >> 4) as u16, (param & 0xf) as u16);
+
+    core::ptr::read_unaligned(param as *const u16)
+}
+
+/// A validated PXE structure, as found in memory
+#[derive(Clone, Copy)]
+pub enum PxeStructure {
+    /// The `!PXE` structure, which holds the PXE API entry point
+    Pxe(&'static [u8]),
+
+    /// The legacy `PXENV+` structure, which links to the `!PXE` structure
+    Pxenv(&'static [u8]),
+}
+
+/// Check if there is a valid `!PXE` or `PXENV+` structure at the linear
+/// address `addr`. This validates the signature, length, and checksum.
+pub fn pxe_struct_at(addr: usize) -> Option<PxeStructure> {
+    // Get the signature and length of the structure
+    let header = unsafe { core::slice::from_raw_parts(addr as *const u8, 9) };
+    let (length, pxe) = if &header[..4] == b"!PXE" && header[4] == 0x58 {
+        (0x58, true)
+    } else if &header[..6] == b"PXENV+" && header[8] == 0x2c {
+        (0x2c, false)
+    } else {
+        return None;
+    };
+
+    // The whole structure must sum to zero
+    let st = unsafe {
+        core::slice::from_raw_parts(addr as *const u8, length)
+    };
+    let checksum = st.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+    if checksum != 0 {
+        return None;
+    }
+
+    Some(if pxe { PxeStructure::Pxe(st) } else { PxeStructure::Pxenv(st) })
+}
+
+/// Locate the PXE structures by scanning conventional memory, rather than
+/// asking for them with the int 0x1a installation check. The PXE stack places
+/// them on a paragraph boundary between 0x10000 and the top of conventional
+/// memory as reported in the BIOS data area. The `!PXE` structure is
+/// preferred, as it holds the PXE API entry point.
+pub fn get_pxe_struct_address() -> Option<PxeStructure> {
+    // Get the top of conventional memory from the BIOS data area, it is
+    // stored as a number of KiB
+    let top = unsafe { core::ptr::read_unaligned(0x413 as *const u16) };
+    let top = core::cmp::min(top as usize * 1024, 0xa0000);
+
+    // Scan for the structures, remembering the first PXENV+
+    let mut pxenv = None;
+    for addr in (0x10000..top.saturating_sub(0x58)).step_by(16) {
+        match pxe_struct_at(addr) {
+            Some(pxe @ PxeStructure::Pxe(_))   => return Some(pxe),
+            Some(pxe @ PxeStructure::Pxenv(_)) => pxenv = pxenv.or(Some(pxe)),
+            None                               => {}
+        }
+    }
+
+    pxenv
+}
@@ -0,0 +1,75 @@
+//! Hardware self-tests run before the kernel is downloaded, such that broken
+//! hardware is not mistaken for kernel bugs
+
+use core::sync::atomic::Ordering;
+use crate::BOOT_ARGS;
+
+/// Print the result of the test `name` and pass the result through
+fn report(name: &str, passed: bool) -> bool {
+    print!("Self-test {:<24} {}\n", name, if passed { "PASS" } else { "FAIL" });
+    passed
+}
+
+/// Send a byte through every serial port in loopback mode
+fn serial_loopback() -> bool {
+    BOOT_ARGS.serial.lock().as_mut().map_or(false, |x| x.loopback_test())
+}
+
+/// Write and read back a word in every page of free physical memory, restoring
+/// the original contents afterwards
+fn memory() -> bool {
+    let pmem = BOOT_ARGS.free_memory.lock();
+    let pmem = pmem.as_ref().expect("Physical memory not initialized yet");
+
+    for range in pmem.entries() {
+        // Only test memory we can address
+        let end = core::cmp::min(range.end, core::usize::MAX as u64);
+
+        // Test the first aligned word in each page of the range
+        let start = (range.start + 7) & !7;
+        for paddr in (start..end.saturating_sub(7)).step_by(4096) {
+            let ptr = paddr as usize as *mut u64;
+
+            unsafe {
+                let orig = core::ptr::read_volatile(ptr);
+                for &pattern in &[0x5555_5555_5555_5555u64,
+                                  0xaaaa_aaaa_aaaa_aaaa] {
+                    core::ptr::write_volatile(ptr, pattern);
+                    if core::ptr::read_volatile(ptr) != pattern {
+                        print!("Memory error at {:#x}\n", paddr);
+                        return false;
+                    }
+                }
+                core::ptr::write_volatile(ptr, orig);
+            }
+        }
+    }
+
+    true
+}
+
+/// Make sure CPUID reports the same information twice in a row
+fn cpuid_consistency() -> bool {
+    unsafe {
+        cpu::cpuid(0, 0) == cpu::cpuid(0, 0) &&
+            cpu::cpuid(1, 0).0 == cpu::cpuid(1, 0).0
+    }
+}
+
+/// Make sure the TSC is counting forwards
+fn rdtsc_monotonic() -> bool {
+    let first = cpu::rdtsc();
+    cpu::rdtsc() > first
+}
+
+/// Run all of the self-tests, recording whether they all passed in the boot
+/// arguments
+pub fn run() {
+    let mut passed = true;
+    passed &= report("serial loopback",    serial_loopback());
+    passed &= report("physical memory",    memory());
+    passed &= report("CPUID consistency",  cpuid_consistency());
+    passed &= report("RDTSC monotonicity", rdtsc_monotonic());
+
+    BOOT_ARGS.selftest_passed.store(passed, Ordering::SeqCst);
+}
@@ -0,0 +1,125 @@
+//! Timekeeping routines for the bootloader
+
+use lockcell::LockCell;
+
+/// Frequency of the PIT input clock, in Hz
+const PIT_HZ: u64 = 1_193_182;
+
+/// The calibrated frequency of the TSC in Hz, if it has been calibrated yet
+static TSC_HZ: LockCell<Option<u64>> = LockCell::new(None);
+
+/// Start a one-shot countdown of `ticks` PIT ticks on PIT channel 0
+unsafe fn pit0_start(ticks: u16) {
+    // Channel 0, lobyte/hibyte access, mode 0 (interrupt on terminal
+    // count), binary
+    cpu::out8(0x43, 0b00_11_000_0);
+
+    // Load the count, which starts the countdown
+    cpu::out8(0x40, ticks as u8);
+    cpu::out8(0x40, (ticks >> 8) as u8);
+}
+
+/// Wait for a countdown started with `pit0_start` to reach zero
+unsafe fn pit0_wait() {
+    loop {
+        // Read-back command, latch the status of channel 0
+        cpu::out8(0x43, 0b11_10_001_0);
+
+        // The output of the channel goes high once the count reaches zero
+        if (cpu::in8(0x40) & 0x80) != 0 {
+            break;
+        }
+
+        // The countdown lasts milliseconds, this is a long wait
+        cpu::pause();
+    }
+}
+
+/// Restore PIT channel 0 to the mode 3 square wave with the maximum period,
+/// which is what the BIOS expects for its timer tick
+unsafe fn pit0_restore() {
+    cpu::out8(0x43, 0b00_11_011_0);
+    cpu::out8(0x40, 0);
+    cpu::out8(0x40, 0);
+}
+
+/// Delay for `ms` milliseconds using PIT channel 0. This does not depend on
+/// the TSC, and thus can be used before it has been calibrated.
+pub fn pit_delay_ms(ms: u32) {
+    let mut remaining = ms as u64;
+
+    unsafe {
+        // The PIT can only count down 65535 ticks at a time, so delay in
+        // chunks of at most 50 milliseconds
+        while remaining > 0 {
+            let chunk = core::cmp::min(remaining, 50);
+            pit0_start((chunk * PIT_HZ / 1000) as u16);
+            pit0_wait();
+            remaining -= chunk;
+        }
+
+        pit0_restore();
+    }
+}
+
+/// Measure the frequency of the TSC in Hz by timing 10 milliseconds on PIT
+/// channel 0. The PIT is polled directly, so this works regardless of whether
+/// IRQ 0 is masked.
+fn calibrate_tsc_with_pit() -> u64 {
+    // Number of PIT ticks in 10 milliseconds
+    const PIT_TICKS: u16 = 11932;
+
+    unsafe {
+        pit0_start(PIT_TICKS);
+        let start = cpu::rdtsc();
+        pit0_wait();
+        let elapsed = cpu::rdtsc() - start;
+        pit0_restore();
+
+        // Project the 10 millisecond measurement out to a second
+        elapsed * 100
+    }
+}
+
+/// Get the frequency of the TSC in Hz as reported by CPUID, if the CPU reports
+/// it. This uses the TSC/crystal ratio and the crystal frequency from leaf
+/// 0x15, which many CPUs only partially enumerate.
+///
+/// The base frequency from leaf 0x16 is not used, as it is only the nominal
+/// processor frequency, which is not necessarily the frequency of the TSC.
+fn read_tsc_frequency_from_cpuid() -> Option<u64> {
+    unsafe {
+        // Make sure leaf 0x15 is supported
+        if cpu::cpuid(0, 0).0 < 0x15 {
+            return None;
+        }
+
+        // Get the TSC/crystal clock ratio and the crystal frequency, any of
+        // which are zero if not enumerated
+        let (denominator, numerator, crystal_hz, _) = cpu::cpuid(0x15, 0);
+        if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+            return None;
+        }
+
+        Some(crystal_hz as u64 * numerator as u64 / denominator as u64)
+    }
+}
+
+/// Get the frequency of the TSC in Hz, calibrating it if needed
+pub fn tsc_hz() -> u64 {
+    let mut tsc_hz = TSC_HZ.lock();
+
+    if tsc_hz.is_none() {
+        // Prefer the exact frequency from CPUID, otherwise measure it
+        *tsc_hz = Some(read_tsc_frequency_from_cpuid()
+            .unwrap_or_else(calibrate_tsc_with_pit));
+    }
+
+    tsc_hz.unwrap()
+}
+
+/// Convert a number of TSC ticks into nanoseconds
+pub fn tsc_to_ns(ticks: u64) -> u64 {
+    // Done in MHz to prevent overflows for multi-second spans
+    ticks * 1000 / core::cmp::max(tsc_hz() / 1_000_000, 1)
+}
@@ -0,0 +1,89 @@
+//! Support for hardware watchdogs described by the ACPI WDAT table
+
+use boot_args::{WatchdogTimer, WatchdogInstruction};
+use boot_args::WATCHDOG_MAX_INSTRUCTIONS;
+
+/// WDAT watchdog action which resets the countdown
+const WDAT_ACTION_RESET: u8 = 1;
+
+/// Generic address structure address space for system I/O
+const GAS_SYSTEM_IO: u8 = 1;
+
+/// Locate the WDAT table and get the watchdog it describes. Only watchdogs
+/// which are reset entirely through I/O ports are supported.
+pub fn init() -> Option<WatchdogTimer> {
+    let (table, len) = crate::acpi::find_table(b"WDAT")?;
+
+    // Routine to read from the WDAT table
+    let read = |off: usize, size: usize| -> Option<u32> {
+        if off.checked_add(size)? > len {
+            return None;
+        }
+
+        unsafe {
+            Some(match size {
+                1 => core::ptr::read_unaligned((table + off) as *const u8)
+                    as u32,
+                2 => core::ptr::read_unaligned((table + off) as *const u16)
+                    as u32,
+                _ => core::ptr::read_unaligned((table + off) as *const u32),
+            })
+        }
+    };
+
+    let mut watchdog = WatchdogTimer {
+        period_ms:        read(48, 4)?,
+        max_count:        read(52, 4)?,
+        num_instructions: 0,
+        instructions:     [WatchdogInstruction {
+            port: 0, width: 0, instruction: 0, bit_offset: 0, value: 0,
+            mask: 0,
+        }; WATCHDOG_MAX_INSTRUCTIONS],
+    };
+
+    // Go through all the instructions, saving the ones which reset the
+    // watchdog
+    for ii in 0..read(64, 4)? as usize {
+        let off = 68 + ii * 24;
+
+        if read(off, 1)? as u8 != WDAT_ACTION_RESET {
+            continue;
+        }
+
+        // We can only pet watchdogs which use I/O ports
+        if read(off + 4, 1)? as u8 != GAS_SYSTEM_IO ||
+                read(off + 8, 4)? > 0xffff || read(off + 12, 4)? != 0 {
+            return None;
+        }
+
+        // Determine the access width from the access size, otherwise from the
+        // register bit width
+        let width = match read(off + 7, 1)? {
+            1 => 1,
+            2 => 2,
+            3 => 4,
+            _ => core::cmp::max(read(off + 5, 1)? / 8, 1) as u8,
+        };
+
+        let slot = watchdog.instructions
+            .get_mut(watchdog.num_instructions as usize)?;
+        *slot = WatchdogInstruction {
+            port:        read(off + 8, 2)? as u16,
+            width:       width,
+            instruction: read(off + 1, 1)? as u8,
+            bit_offset:  read(off + 6, 1)?,
+            value:       read(off + 16, 4)?,
+            mask:        read(off + 20, 4)?,
+        };
+        watchdog.num_instructions += 1;
+    }
+
+    if watchdog.num_instructions == 0 {
+        return None;
+    }
+
+    print!("Found WDAT watchdog with a {} ms timeout\n",
+           watchdog.period_ms as u64 * watchdog.max_count as u64);
+
+    Some(watchdog)
+}
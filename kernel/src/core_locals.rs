@@ -2,10 +2,9 @@
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::mm::PageFreeList;
+use crate::mm::{PageFreeList, PageCache};
 use lockcell::LockCell;
-use page_table::PhysAddr;
-use boot_args::{BootArgs, KERNEL_PHYS_WINDOW_BASE};
+use boot_args::BootArgs;
 
 /// A counter of all cores online
 static CORES_ONLINE: AtomicUsize = AtomicUsize::new(0);
@@ -28,6 +27,9 @@ pub struct CoreLocals {
 
     /// A core local free list of pages
     pub free_list: LockCell<PageFreeList>,
+
+    /// A lock-free cache of pages which can be used from interrupt handlers
+    pub page_cache: PageCache,
 }
 
 /// Empty marker trait that requires `Sync`, such that we can compile-time
@@ -59,28 +61,27 @@ pub fn get_core_locals() -> &'static CoreLocals {
 }
 
 /// Initialize the locals for this core
-pub fn init(boot_args: PhysAddr) {
-    // Convert the physical boot args pointer into the linear mapping
-    let boot_args: &'static BootArgs = unsafe {
-        &*((boot_args.0 + KERNEL_PHYS_WINDOW_BASE) as *const BootArgs)
-    };
+pub fn init(boot_args: &'static BootArgs) {
+    // Allocate the core locals
+    let core_local_ptr = {
+        // Get access to the physical memory allocator
+        let mut pmem = boot_args.free_memory.lock();
+        let pmem = pmem.as_mut().unwrap();
 
-    // Get access to the physical memory allocator
-    let mut pmem = boot_args.free_memory.lock();
-    let pmem = pmem.as_mut().unwrap();
+        let paddr = pmem.allocate(
+            core::mem::size_of::<CoreLocals>() as u64,
+            core::mem::align_of::<CoreLocals>() as u64).unwrap();
 
-    // Allocate the core locals
-    let core_local_ptr = pmem.allocate(
-        core::mem::size_of::<CoreLocals>() as u64,
-        core::mem::align_of::<CoreLocals>() as u64).unwrap() +
-        KERNEL_PHYS_WINDOW_BASE as usize;
+        boot_args.phys_to_virt(paddr as u64).0 as usize
+    };
 
     // Construct the core locals
     let core_locals = CoreLocals {
-        address:   core_local_ptr,
-        id:        CORES_ONLINE.fetch_add(1, Ordering::SeqCst),
-        boot_args: boot_args,
-        free_list: LockCell::new(PageFreeList::new()),
+        address:    core_local_ptr,
+        id:         CORES_ONLINE.fetch_add(1, Ordering::SeqCst),
+        boot_args:  boot_args,
+        free_list:  LockCell::new(PageFreeList::new()),
+        page_cache: PageCache::new(),
     };
 
     unsafe {
@@ -88,5 +89,9 @@ pub fn init(boot_args: PhysAddr) {
         core::ptr::write(core_local_ptr as *mut CoreLocals, core_locals);
         cpu::set_gs_base(core_local_ptr as u64);
     }
+
+    // Now that the core locals are accessible, populate the interrupt page
+    // cache
+    crate::mm::refill_page_cache();
 }
 
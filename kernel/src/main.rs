@@ -15,23 +15,31 @@ extern crate core_reqs;
 mod panic;
 mod mm;
 
+use core::sync::atomic::Ordering;
 use page_table::PhysAddr;
+use boot_args::BootArgs;
+
+/// Number of milliseconds the BSP waits for the APs to check in before it
+/// gives up on the ones which haven't
+const AP_STARTUP_TIMEOUT_MS: u64 = 1000;
 
 /// Release the early boot stack such that other cores can use it by marking
 /// it as available
-fn release_early_stack() {
-    use core::sync::atomic::{AtomicU8, Ordering};
+fn release_early_stack(boot_args: &BootArgs) {
+    use core::sync::atomic::AtomicU8;
 
     unsafe {
-        (*((0x7e00 + boot_args::KERNEL_PHYS_WINDOW_BASE) as
-           *const AtomicU8)).store(1, Ordering::SeqCst);
+        (*(boot_args.phys_to_virt(0x7e00).0 as *const AtomicU8))
+            .store(1, Ordering::SeqCst);
     }
 }
 
+/// Kernel entry point, `boot_args` is the virtual address of the bootloader
+/// arguments as seen through the physical window
 #[no_mangle]
-pub extern fn entry(boot_args: PhysAddr) -> ! {
+pub extern fn entry(boot_args: &'static BootArgs) -> ! {
     // Release the early boot stack, now that we have our own stack
-    release_early_stack();
+    release_early_stack(boot_args);
 
     // Initialize the core locals
     core_locals::init(boot_args);
@@ -39,6 +47,19 @@ pub extern fn entry(boot_args: PhysAddr) -> ! {
     if cpu::is_bsp() {
         // One-time initialization for the whole kernel
 
+        // Make sure we see `BootArgs` the same as the bootloader does
+        boot_args.check_layout();
+
+        // Dump the physical memory map for diagnostics
+        if let Some(serial) = boot_args.serial.lock().as_mut() {
+            boot_args.log_physical_memory_map(serial);
+        }
+
+        // Global initialization is done, let the APs out of the bootloader
+        // and into the kernel once they're started
+        boot_args.ap_entry_spin.release_all(cpu::apic_id(),
+                                            entry as *const () as u64);
+
         // Bring up all other cores
         unsafe {
             cpu::wrmsr(0x1b, 0xfee0_0000 | (1 << 11) |
@@ -48,6 +69,14 @@ pub extern fn entry(boot_args: PhysAddr) -> ! {
             mm::write_phys(PhysAddr(0xfee0_0300), 0xc4608u32);
             mm::write_phys(PhysAddr(0xfee0_0300), 0xc4608u32);
         }
+
+        // Wait for every AP to be done with the bootloader, but don't hang
+        // the boot on CPUs listed in the MADT which never come up
+        let missing = boot_args.wait_for_cpus(AP_STARTUP_TIMEOUT_MS);
+        if missing != 0 {
+            print!("{} CPUs never checked in, continuing with {} online\n",
+                   missing, boot_args.online_cpus.load(Ordering::SeqCst));
+        }
     }
 
     print!("Core ID {} online!\n", core!().id);
@@ -1,11 +1,15 @@
 use core::alloc::{Layout, GlobalAlloc};
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use rangeset::Range;
-use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
 use boot_args::KERNEL_VMEM_BASE;
 use page_table::{PhysMem, PhysAddr, PageType, VirtAddr};
 
+#[cfg(feature = "alloc-tracking")]
+use lockcell::LockCell;
+#[cfg(feature = "alloc-tracking")]
+use serial::SerialPort;
+
 /// Base address for virtual allocations
 static NEXT_FREE_VADDR: AtomicU64 = AtomicU64::new(KERNEL_VMEM_BASE);
 
@@ -16,10 +20,12 @@ pub unsafe fn read_phys<T>(paddr: PhysAddr) -> T {
     let end = (core::mem::size_of::<T>() as u64).checked_sub(1).and_then(|x| {
         x.checked_add(paddr.0)
     }).expect("Integer overflow on read_phys");
-    assert!(end < KERNEL_PHYS_WINDOW_SIZE,
+    assert!(end < core!().boot_args.kernel_phys_window_size
+                .load(Ordering::SeqCst),
             "Physical address outside of window");
 
-    core::ptr::read_volatile((KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut T)
+    core::ptr::read_volatile(
+        core!().boot_args.phys_to_virt(paddr.0).0 as *mut T)
 }
 
 /// Write to a physical address containing a type `T`. This just handles the
@@ -28,11 +34,12 @@ pub unsafe fn write_phys<T>(paddr: PhysAddr, val: T) {
     let end = (core::mem::size_of::<T>() as u64).checked_sub(1).and_then(|x| {
         x.checked_add(paddr.0)
     }).expect("Integer overflow on write_phys");
-    assert!(end < KERNEL_PHYS_WINDOW_SIZE,
+    assert!(end < core!().boot_args.kernel_phys_window_size
+                .load(Ordering::SeqCst),
             "Physical address outside of window");
 
     core::ptr::write_volatile(
-        (KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut T, val);
+        core!().boot_args.phys_to_virt(paddr.0).0 as *mut T, val);
 }
 
 /// The metadata on a freed page present in the free list. We don't just
@@ -58,7 +65,8 @@ struct FreeListNode {
 
 impl FreeListNode {
     unsafe fn from_raw<'a>(paddr: PhysAddr) -> &'a mut FreeListNode {
-        &mut *((KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut FreeListNode)
+        &mut *(core!().boot_args.phys_to_virt(paddr.0).0
+               as *mut FreeListNode)
     }
 }
 
@@ -73,7 +81,7 @@ impl PageFreeList {
     }
 
     /// Get a page from the free list
-    unsafe fn pop(&mut self) -> PhysAddr {
+    unsafe fn pop(&mut self) -> Option<PhysAddr> {
         // If the free list is empty
         if self.head == PhysAddr(0) {
             const FREE_LIST_BATCH: u64 = 1024 * 1024;
@@ -88,8 +96,7 @@ impl PageFreeList {
                 let phys_mem     = phys_mem.as_mut().unwrap();
 
                 // Bulk allocate some memory to populate the empty free list
-                phys_mem.allocate(FREE_LIST_BATCH, 4096)
-                    .expect("Failed to allocate physical memory") as u64
+                phys_mem.allocate(FREE_LIST_BATCH, 4096)? as u64
             };
 
             // Populate the free list
@@ -108,7 +115,7 @@ impl PageFreeList {
             // Note that we used this entry
             node.free_slots += 1;
 
-            free
+            Some(free)
         } else {
             // The `free_pages` for this level is empty, thus, pop the entire
             // node and use it as the free page
@@ -119,7 +126,7 @@ impl PageFreeList {
             // Point the head to the next node
             self.head = node.next;
 
-            old
+            Some(old)
         }
     }
 
@@ -153,6 +160,208 @@ impl PageFreeList {
     }
 }
 
+/// Number of pages held in each core's interrupt-safe page cache
+const PAGE_CACHE_SIZE: usize = 64;
+
+/// Number of pages the page cache is refilled to. The rest of the slots are
+/// left empty, such that pages freed from interrupt handlers always have
+/// somewhere to go without taking a lock.
+const PAGE_CACHE_FILL: usize = PAGE_CACHE_SIZE / 2;
+
+/// A small, lock-free cache of free 4 KiB pages. This can be used from
+/// interrupt handlers on this core, which may have interrupted code which is
+/// holding the free list or the physical memory locks. Every slot is either
+/// zero (empty) or holds the physical address of a free page.
+pub struct PageCache {
+    /// Physical addresses of the cached pages
+    pages: [AtomicU64; PAGE_CACHE_SIZE],
+
+    /// Number of non-empty slots in `pages`
+    cached: AtomicUsize,
+}
+
+impl PageCache {
+    pub fn new() -> Self {
+        // All-zeros is a valid bit pattern for atomics and zero marks a slot
+        // as empty.
+        unsafe { core::mem::zeroed() }
+    }
+
+    /// Get the number of pages currently in the cache
+    pub fn len(&self) -> usize {
+        self.cached.load(Ordering::SeqCst)
+    }
+
+    /// Take a page from the cache, without acquiring any locks
+    #[allow(dead_code)]
+    pub fn pop(&self) -> Option<PhysAddr> {
+        for slot in self.pages.iter() {
+            // Take ownership of whatever is in this slot
+            let page = slot.swap(0, Ordering::SeqCst);
+            if page != 0 {
+                self.cached.fetch_sub(1, Ordering::SeqCst);
+                return Some(PhysAddr(page));
+            }
+        }
+
+        None
+    }
+
+    /// Put a page into the cache, without acquiring any locks. Returns `false`
+    /// if there was no room for the page in the cache.
+    pub fn push(&self, page: PhysAddr) -> bool {
+        assert!(page.0 != 0, "Cannot cache the zero page");
+
+        for slot in self.pages.iter() {
+            // Place the page in the first empty slot
+            if slot.compare_exchange(0, page.0, Ordering::SeqCst,
+                                     Ordering::SeqCst).is_ok() {
+                self.cached.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Refill the current core's page cache from its free list. This acquires the
+/// free list lock, and thus must never be called from an interrupt handler.
+pub fn refill_page_cache() {
+    let cache = &core!().page_cache;
+
+    // Nothing to do if the cache is already filled
+    if cache.len() >= PAGE_CACHE_FILL {
+        return;
+    }
+
+    // Get access to the free list
+    let mut free_list = core!().free_list.lock();
+
+    while cache.len() < PAGE_CACHE_FILL {
+        // Out of memory, leave the cache as full as we could get it
+        let page = match unsafe { free_list.pop() } {
+            Some(page) => page,
+            None       => break,
+        };
+        if !cache.push(page) {
+            // Someone else filled the cache behind our back, give the page
+            // back to the free list
+            unsafe { free_list.push(page); }
+            break;
+        }
+    }
+}
+
+/// Allocate `num_pages` physically contiguous 4 KiB pages, returning the
+/// physical address of the first page. This is intended for DMA buffers.
+///
+/// These allocations come directly from the global physical memory pool, and
+/// pages which have been handed out to the per-core free lists are never
+/// coalesced back into it. Thus the largest guaranteed contiguous allocation
+/// is the largest range remaining in `BootArgs::free_memory`.
+#[allow(dead_code)]
+pub fn alloc_contiguous(num_pages: u64) -> Option<u64> {
+    let size = num_pages.checked_mul(4096)?;
+
+    // Get access to physical memory
+    let mut phys_mem = core!().boot_args.free_memory.lock();
+    let phys_mem     = phys_mem.as_mut().unwrap();
+
+    // Find a free range large enough, splitting it if needed
+    phys_mem.allocate(size, 4096).map(|x| x as u64)
+}
+
+/// Maximum number of outstanding allocations which can be tracked
+#[cfg(feature = "alloc-tracking")]
+const ALLOC_TRACKER_SIZE: usize = 1024;
+
+/// An outstanding physical memory allocation
+#[cfg(feature = "alloc-tracking")]
+#[derive(Clone, Copy)]
+struct AllocRecord {
+    /// Physical address of the allocation
+    paddr: u64,
+
+    /// Size of the allocation in bytes, zero if this record is unused
+    size: u64,
+}
+
+/// Tracks all outstanding physical memory allocations, such that leaks can be
+/// found
+#[cfg(feature = "alloc-tracking")]
+struct AllocTracker {
+    /// Outstanding allocations
+    records: [AllocRecord; ALLOC_TRACKER_SIZE],
+
+    /// Number of allocations which could not be tracked as the table was full
+    dropped: u64,
+}
+
+/// All tracked physical memory allocations
+#[cfg(feature = "alloc-tracking")]
+static ALLOC_TRACKER: LockCell<AllocTracker> = LockCell::new(AllocTracker {
+    records: [AllocRecord { paddr: 0, size: 0 }; ALLOC_TRACKER_SIZE],
+    dropped: 0,
+});
+
+/// Record an allocation in the allocation tracker
+#[cfg(feature = "alloc-tracking")]
+fn track_alloc(paddr: PhysAddr, size: u64) {
+    let mut tracker = ALLOC_TRACKER.lock();
+
+    // Find an empty slot for the allocation
+    if let Some(record) = tracker.records.iter_mut().find(|x| x.size == 0) {
+        *record = AllocRecord { paddr: paddr.0, size };
+    } else {
+        tracker.dropped += 1;
+    }
+}
+
+/// Remove an allocation from the allocation tracker
+#[cfg(feature = "alloc-tracking")]
+fn track_free(paddr: PhysAddr) {
+    let mut tracker = ALLOC_TRACKER.lock();
+
+    if let Some(record) = tracker.records.iter_mut()
+            .find(|x| x.size != 0 && x.paddr == paddr.0) {
+        record.size = 0;
+    }
+}
+
+/// Print all outstanding tracked physical memory allocations to `serial`
+#[cfg(feature = "alloc-tracking")]
+pub fn dump_alloc_table(serial: &mut SerialPort) {
+    use core::fmt::Write;
+
+    let tracker = ALLOC_TRACKER.lock();
+
+    let _ = write!(serial, "Outstanding physical allocations:\n");
+    for record in tracker.records.iter().filter(|x| x.size != 0) {
+        let _ = write!(serial, "    {:#018x} {:#10x} bytes\n",
+                       record.paddr, record.size);
+    }
+    let _ = write!(serial, "{} allocations were not tracked\n",
+                   tracker.dropped);
+}
+
+/// Pattern written over physical memory when it is freed in debug builds
+const POISON_FREED: u64 = 0xdeadbeefdeadbeef;
+
+/// Pattern written over physical memory when it is allocated in debug builds
+const POISON_UNINIT: u64 = 0xcafebabecafebabe;
+
+/// In debug builds, fill the physical memory at `paddr` for `size` bytes with
+/// `pattern`, such that use-after-free and use-before-init bugs are easier to
+/// spot. This does nothing in release builds.
+fn poison(paddr: PhysAddr, size: u64, pattern: u64) {
+    if cfg!(debug_assertions) {
+        for off in (0..size & !7).step_by(8) {
+            unsafe { write_phys(PhysAddr(paddr.0 + off), pattern); }
+        }
+    }
+}
+
 /// A wrapper on a range set to allow implementing the `PhysMem` trait
 pub struct PhysicalMemory;
 
@@ -164,16 +373,22 @@ impl PhysMem for PhysicalMemory {
         }).expect("Integer overflow on physical memory translation");
 
         // Make sure this physical address fits inside our window
-        assert!(end < KERNEL_PHYS_WINDOW_SIZE,
+        assert!(end < core!().boot_args.kernel_phys_window_size
+                    .load(Ordering::SeqCst),
                 "Physical address outside of physical window");
 
         // Convert the physical address into linear mapping view address
-        (paddr.0 + KERNEL_PHYS_WINDOW_BASE) as *mut u8
+        core!().boot_args.phys_to_virt(paddr.0).0 as *mut u8
     }
 
-    fn alloc_phys(&mut self, layout: Layout) -> PhysAddr {
-        if layout.size() == 4096 && layout.align() == 4096 {
-            unsafe { core!().free_list.lock().pop() }
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        let alc = if layout.size() == 4096 && layout.align() == 4096 {
+            // Top off the interrupt page cache if it is starting to run low
+            if core!().page_cache.len() < PAGE_CACHE_FILL / 2 {
+                refill_page_cache();
+            }
+
+            unsafe { core!().free_list.lock().pop()? }
         } else {
             // Get access to physical memory
             let mut phys_mem = core!().boot_args.free_memory.lock();
@@ -182,13 +397,26 @@ impl PhysMem for PhysicalMemory {
             // Could not satisfy allocation from free list, allocate
             // directly from the physical memory pool
             let alc = phys_mem.allocate(layout.size() as u64,
-                                        layout.align() as u64)
-                .expect("Failed to allocate physical memory");
+                                        layout.align() as u64)?;
             PhysAddr(alc as u64)
-        }
+        };
+
+        // Mark the memory as uninitialized
+        poison(alc, layout.size() as u64, POISON_UNINIT);
+
+        #[cfg(feature = "alloc-tracking")]
+        track_alloc(alc, layout.size() as u64);
+
+        Some(alc)
     }
 
     fn free_phys(&mut self, phys: PhysAddr, size: u64) {
+        // Mark the memory as freed
+        poison(phys, size, POISON_FREED);
+
+        #[cfg(feature = "alloc-tracking")]
+        track_free(phys);
+
         if (phys.0 & 0xfff) == 0 && size == 4096 {
             // Get access to the free list
             unsafe { core!().free_list.lock().push(phys); }
@@ -206,6 +434,40 @@ impl PhysMem for PhysicalMemory {
     }
 }
 
+/// A `PhysMem` implementation which never acquires any locks, and thus is safe
+/// to use from interrupt handlers. This can only allocate and free 4 KiB
+/// pages, which are drawn from and returned to the core's `PageCache`.
+///
+/// The cache is only ever refilled to half of its size, thus interrupt
+/// handlers can free up to `PAGE_CACHE_SIZE - PAGE_CACHE_FILL` more pages
+/// than they allocate before the cache overflows.
+#[allow(dead_code)]
+pub struct InterruptPhysicalMemory;
+
+impl PhysMem for InterruptPhysicalMemory {
+    unsafe fn translate(&mut self, paddr: PhysAddr, size: usize) -> *mut u8 {
+        PhysicalMemory.translate(paddr, size)
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        assert!(layout.size() == 4096 && layout.align() == 4096,
+                "Only 4 KiB pages can be allocated from interrupts");
+
+        core!().page_cache.pop()
+    }
+
+    fn free_phys(&mut self, phys: PhysAddr, size: u64) {
+        assert!((phys.0 & 0xfff) == 0 && size == 4096,
+                "Only 4 KiB pages can be freed from interrupts");
+
+        // Giving the page to the free list or the global physical memory
+        // pool would take a lock the interrupted code may hold
+        assert!(core!().page_cache.push(phys),
+                "Interrupt page cache overflowed, more pages were freed than \
+                 allocated from interrupts");
+    }
+}
+
 /// The global allocator for the bootloader, this just uses physical memory as
 /// a backing and does not handle any fancy things like fragmentation. Use this
 /// carefully.
@@ -233,7 +495,7 @@ impl GlobalAllocator {
 
         // Map in the memory as RW
         page_table.map(&mut pmem, VirtAddr(vaddr), PageType::Page4K,
-            alignsize, true, true, false)?;
+            alignsize, true, true, false).ok()?;
 
         // Allocation success, `vaddr` now is valid as read-write for
         // `alignsize` bytes!
@@ -15,6 +15,14 @@ fn panic(info: &PanicInfo) -> ! {
 
     print!("\n");
 
+    // Dump all outstanding physical allocations
+    #[cfg(feature = "alloc-tracking")]
+    {
+        if let Some(serial) = core!().boot_args.serial.lock().as_mut() {
+            crate::mm::dump_alloc_table(serial);
+        }
+    }
+
     cpu::halt();
 }
 
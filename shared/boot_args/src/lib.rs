@@ -9,33 +9,520 @@
 
 #![no_std]
 
-use core::sync::atomic::AtomicU64;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 use serial::SerialPort;
 use rangeset::RangeSet;
 use lockcell::LockCell;
-use page_table::PageTable;
+use page_table::{PageTable, PhysMem, VirtAddr};
 
 /// Base vaddr to use for kernel stacks
 pub const KERNEL_STACKS_BASE: u64 = 0x0000_7473_0000_0000;
 
-/// The virtual base in the kernel page tables where physical memory is
+/// The default virtual base in the kernel page tables where physical memory is
 /// linearally mapped. Such that a dereference of `KERNEL_PHYS_WINDOW_BASE`
 /// in the kernel address space, will be accessing `0` in physical memory.
+///
+/// The base actually in use is `BootArgs::phys_window_base`, which is what
+/// both the bootloader and kernel should use at runtime.
 pub const KERNEL_PHYS_WINDOW_BASE: u64 = 0xffff_cafe_0000_0000;
 
 /// The base virtual address to use for dynamic virtual allocations
 pub const KERNEL_VMEM_BASE: u64 = 0xffff_8000_0000_0000;
 
+/// The virtual base in the kernel page tables where the initrd, if present,
+/// is mapped in as read-only
+pub const KERNEL_INITRD_BASE: u64 = 0x0000_1717_0000_0000;
+
+/// The virtual base in the kernel page tables where the kernel heap is mapped
+pub const KERNEL_HEAP_BASE: u64 = 0x0000_4ea9_0000_0000;
+
+/// Size of the kernel heap which is mapped in by the bootloader (in bytes)
+pub const KERNEL_HEAP_SIZE: u64 = 16 * 1024 * 1024;
+
 /// Size to allocate for kernel stacks
 pub const KERNEL_STACK_SIZE: u64 = 32 * 1024;
 
 /// Padding deadspace to add between kernel stacks
 pub const KERNEL_STACK_PAD: u64 = 32 * 1024;
 
+/// Size of the virtual region starting at `KERNEL_STACKS_BASE` which kernel
+/// stacks may be allocated in, enough for a stack for every APIC ID
+pub const MAX_STACK_REGION_SIZE: u64 =
+    (KERNEL_STACK_SIZE + KERNEL_STACK_PAD) * MAX_APIC_IDS as u64;
+
 /// Size of the kernel physical window (in bytes)
 pub const KERNEL_PHYS_WINDOW_SIZE: u64 = 32 * 1024 * 1024 * 1024;
 
+/// The maximum number of APIC IDs which can have a `PageSlab`
+pub const MAX_APIC_IDS: usize = 256;
+
+/// Number of pages which can be held in a `PageSlab`
+pub const PAGE_SLAB_SIZE: usize = 64;
+
+/// Number of pages left in a `PageSlab` at which point it is replenished
+pub const PAGE_SLAB_LOW: usize = 8;
+
+/// Maximum number of E820 entries which are saved from the BIOS memory map
+pub const MAX_E820_ENTRIES: usize = 128;
+
+/// Default number of seconds to wait after a bootloader panic before
+/// rebooting, with the `crash-reboot` feature of the bootloader
+pub const DEFAULT_REBOOT_DELAY_SECS: u32 = 10;
+
+/// A single entry of the BIOS E820 memory map
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct E820Entry {
+    /// Physical address of the start of the region
+    pub base: u64,
+
+    /// Size of the region in bytes
+    pub size: u64,
+
+    /// Type of the region, 1 is usable RAM
+    pub typ: u32,
+
+    /// Padding such that the layout is identical in 32 and 64-bit modes
+    pub _pad: u32,
+}
+
+/// The raw memory map as reported by the BIOS E820 call
+#[repr(C)]
+pub struct E820Map {
+    /// The entries, `entries[..count]` are valid
+    pub entries: [E820Entry; MAX_E820_ENTRIES],
+
+    /// Number of valid entries in `entries`
+    pub count: u32,
+}
+
+impl E820Map {
+    /// Create a new empty memory map
+    pub const fn new() -> Self {
+        E820Map {
+            entries: [E820Entry { base: 0, size: 0, typ: 0, _pad: 0 };
+                MAX_E820_ENTRIES],
+            count: 0,
+        }
+    }
+
+    /// Get the valid entries of the memory map
+    pub fn entries(&self) -> &[E820Entry] {
+        &self.entries[..self.count as usize]
+    }
+}
+
+/// The location of a single logical CPU in the CPU topology
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct CpuTopoEntry {
+    /// x2APIC ID of the logical CPU
+    pub apic_id: u32,
+
+    /// Physical package the CPU is in
+    pub package_id: u8,
+
+    /// Core in the package the CPU is on
+    pub core_id: u8,
+
+    /// Hardware thread on the core the CPU is
+    pub thread_id: u8,
+
+    /// Padding such that the layout is identical in 32 and 64-bit modes
+    pub _pad: u8,
+}
+
+/// The package, core, and thread of every CPU which went online, such that
+/// the kernel can tell which CPUs share a physical core or package
+#[repr(C)]
+pub struct CpuTopology {
+    /// Number of physical packages
+    pub packages: u8,
+
+    /// Number of cores in each package
+    pub cores_per_package: u8,
+
+    /// Number of hardware threads on each core
+    pub threads_per_core: u8,
+
+    /// Padding such that the layout is identical in 32 and 64-bit modes
+    pub _pad: u8,
+
+    /// Number of valid entries in `entries`
+    pub count: u32,
+
+    /// The CPUs, `entries[..count]` are valid, in the order they went online
+    pub entries: [CpuTopoEntry; MAX_APIC_IDS],
+}
+
+impl CpuTopology {
+    /// Create a new empty topology
+    pub const fn new() -> Self {
+        CpuTopology {
+            packages:          0,
+            cores_per_package: 0,
+            threads_per_core:  0,
+            _pad:              0,
+            count:             0,
+            entries: [CpuTopoEntry {
+                apic_id: 0, package_id: 0, core_id: 0, thread_id: 0, _pad: 0,
+            }; MAX_APIC_IDS],
+        }
+    }
+
+    /// Get the valid entries of the topology
+    pub fn entries(&self) -> &[CpuTopoEntry] {
+        &self.entries[..self.count as usize]
+    }
+
+    /// Add a CPU to the topology and update the package, core, and thread
+    /// counts
+    pub fn add(&mut self, entry: CpuTopoEntry) {
+        // Ignore CPUs beyond what we can hold
+        if self.count as usize >= self.entries.len() {
+            return;
+        }
+        self.entries[self.count as usize] = entry;
+        self.count += 1;
+
+        // Count the unique packages and cores, IDs are not necessarily
+        // contiguous
+        let entries = self.entries();
+        let mut packages = 0u32;
+        let mut cores    = 0u32;
+        for (ii, ent) in entries.iter().enumerate() {
+            if !entries[..ii].iter().any(|x| x.package_id == ent.package_id) {
+                packages += 1;
+            }
+            if !entries[..ii].iter().any(|x| x.package_id == ent.package_id &&
+                                         x.core_id    == ent.core_id) {
+                cores += 1;
+            }
+        }
+
+        let to_u8 = |x: u32| core::cmp::min(x, 255) as u8;
+        self.packages          = to_u8(packages);
+        self.cores_per_package = to_u8(cores / packages);
+        self.threads_per_core  = to_u8(self.count / cores);
+    }
+}
+
+/// A per-CPU slab of free 4 KiB physical pages, allowing single-page
+/// allocations without taking the global `free_memory` lock. A slab must only
+/// ever be used by the CPU which owns it.
+#[repr(C)]
+pub struct PageSlab {
+    /// Physical addresses of the free pages, `pages[..count]` are valid
+    pub pages: [AtomicU64; PAGE_SLAB_SIZE],
+
+    /// Number of valid entries in `pages`
+    pub count: AtomicU64,
+}
+
+/// Per-APIC ID kernel entry points which APs spin on before entering the
+/// kernel. This is logically an `[AtomicU64; MAX_APIC_IDS]`, however it is
+/// stored as `u64`s such that it can be constructed in a `const fn`, as
+/// atomics cannot be used in array repeat expressions.
+#[repr(C, align(8))]
+pub struct ApEntrySpin(UnsafeCell<[u64; MAX_APIC_IDS]>);
+
+unsafe impl Sync for ApEntrySpin {}
+
+impl ApEntrySpin {
+    /// Create a new set of entry points, with every AP held
+    pub const fn new() -> Self {
+        ApEntrySpin(UnsafeCell::new([0; MAX_APIC_IDS]))
+    }
+
+    /// Get the entry point for the CPU with `apic_id`, zero while the CPU is
+    /// held
+    pub fn get(&self, apic_id: u32) -> &AtomicU64 {
+        assert!((apic_id as usize) < MAX_APIC_IDS,
+            "APIC ID too large for AP entry spin");
+
+        // `AtomicU64` has the same layout as `u64`, and the array is 8-byte
+        // aligned
+        unsafe {
+            &*(self.0.get() as *const AtomicU64).add(apic_id as usize)
+        }
+    }
+
+    /// Spin until the CPU with `apic_id` is released, returning the entry
+    /// point it was released to
+    pub fn wait(&self, apic_id: u32) -> u64 {
+        let entry = self.get(apic_id);
+        loop {
+            let entry_point = entry.load(Ordering::SeqCst);
+            if entry_point != 0 {
+                return entry_point;
+            }
+
+            // The BSP may still be initializing the kernel for a while
+            cpu::pause();
+        }
+    }
+
+    /// Release every AP other than `bsp_apic_id` to `entry_point`
+    pub fn release_all(&self, bsp_apic_id: u32, entry_point: u64) {
+        for apic_id in (0..MAX_APIC_IDS as u32).filter(|&x| x != bsp_apic_id) {
+            self.get(apic_id).store(entry_point, Ordering::SeqCst);
+        }
+    }
+}
+
+/// APIC IDs of every CPU which went online, indexed by the order in which they
+/// went online. This is logically an `[AtomicU32; MAX_APIC_IDS]`, stored as
+/// `u32`s for the same reason as `ApEntrySpin`, such that the IDs can be read
+/// without a lock.
+#[repr(C, align(4))]
+pub struct ApApicIds(UnsafeCell<[u32; MAX_APIC_IDS]>);
+
+unsafe impl Sync for ApApicIds {}
+
+impl ApApicIds {
+    /// Create a new table with every slot empty, that is `0xff`
+    pub const fn new() -> Self {
+        ApApicIds(UnsafeCell::new([0xff; MAX_APIC_IDS]))
+    }
+
+    /// Get the slot for the `cpu_num`th CPU to go online, `None` if there are
+    /// more CPUs than slots
+    pub fn get(&self, cpu_num: usize) -> Option<&AtomicU32> {
+        if cpu_num >= MAX_APIC_IDS {
+            return None;
+        }
+
+        // `AtomicU32` has the same layout as `u32`, and the array is 4-byte
+        // aligned
+        unsafe {
+            Some(&*(self.0.get() as *const AtomicU32).add(cpu_num))
+        }
+    }
+}
+
+/// Location of the kernel's PE export directory, which can be used to look up
+/// the addresses of kernel symbols by name
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct KernelSymbolTable {
+    /// Base virtual address of the kernel image
+    pub image_base: u64,
+
+    /// Virtual address of the export directory in the kernel image. This is a
+    /// virtual address as the loaded image is not physically contiguous.
+    pub export_dir: u64,
+
+    /// Size of the export directory in bytes
+    pub export_size: u64,
+}
+
+impl KernelSymbolTable {
+    /// Look up the virtual address of the exported symbol `name`
+    ///
+    /// This reads the export directory directly out of the loaded kernel
+    /// image, thus it must only be used when the kernel page table is active.
+    pub unsafe fn lookup(&self, name: &str) -> Option<u64> {
+        // Routines to read from the loaded kernel image by RVA
+        let read_u16 = |rva: u64| {
+            core::ptr::read_unaligned((self.image_base + rva) as *const u16)
+        };
+        let read_u32 = |rva: u64| {
+            core::ptr::read_unaligned((self.image_base + rva) as *const u32)
+        };
+
+        // Get the export tables
+        let dir       = self.export_dir - self.image_base;
+        let num_names = read_u32(dir + 0x18) as u64;
+        let functions = read_u32(dir + 0x1c) as u64;
+        let names     = read_u32(dir + 0x20) as u64;
+        let ordinals  = read_u32(dir + 0x24) as u64;
+
+        // The name pointer table is sorted, so binary search it
+        let (mut lo, mut hi) = (0u64, num_names);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+
+            // Compare the nul-terminated name at `mid` against `name`
+            let sym = self.image_base + read_u32(names + mid * 4) as u64;
+            let mut sym_len = 0;
+            while *((sym + sym_len) as *const u8) != 0 {
+                sym_len += 1;
+            }
+            let sym = core::slice::from_raw_parts(
+                sym as *const u8, sym_len as usize);
+
+            match sym.cmp(name.as_bytes()) {
+                core::cmp::Ordering::Less    => lo = mid + 1,
+                core::cmp::Ordering::Greater => hi = mid,
+                core::cmp::Ordering::Equal   => {
+                    let ordinal = read_u16(ordinals + mid * 2) as u64;
+                    let rva     = read_u32(functions + ordinal * 4) as u64;
+
+                    // Exports which point inside the export directory are
+                    // forwarders to other images
+                    if rva >= dir && rva < dir + self.export_size {
+                        return None;
+                    }
+
+                    return Some(self.image_base + rva);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Maximum number of WDAT instructions which can be used to pet a watchdog
+pub const WATCHDOG_MAX_INSTRUCTIONS: usize = 4;
+
+/// WDAT instruction which writes a fixed value to a register
+pub const WDAT_WRITE_VALUE: u8 = 2;
+
+/// WDAT instruction which writes the countdown to a register
+pub const WDAT_WRITE_COUNTDOWN: u8 = 3;
+
+/// WDAT instruction flag to preserve the bits of the register outside of the
+/// mask when writing
+pub const WDAT_PRESERVE_REGISTER: u8 = 0x80;
+
+/// A single I/O port write from the ACPI WDAT table
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct WatchdogInstruction {
+    /// I/O port to access
+    pub port: u16,
+
+    /// Size of the access in bytes, 1, 2, or 4
+    pub width: u8,
+
+    /// The WDAT instruction and its flags
+    pub instruction: u8,
+
+    /// Bit offset of the field in the register
+    pub bit_offset: u32,
+
+    /// Value to write for `WDAT_WRITE_VALUE` instructions
+    pub value: u32,
+
+    /// Mask of the field in the register
+    pub mask: u32,
+}
+
+impl WatchdogInstruction {
+    /// Read the register
+    unsafe fn read(&self) -> u32 {
+        match self.width {
+            1 => cpu::in8(self.port)  as u32,
+            2 => cpu::in16(self.port) as u32,
+            _ => cpu::in32(self.port),
+        }
+    }
+
+    /// Write the register
+    unsafe fn write(&self, val: u32) {
+        match self.width {
+            1 => cpu::out8(self.port,  val as u8),
+            2 => cpu::out16(self.port, val as u16),
+            _ => cpu::out32(self.port, val),
+        }
+    }
+
+    /// Execute this instruction, using `countdown` for countdown writes
+    unsafe fn execute(&self, countdown: u32) {
+        // Get the value for the field
+        let val = match self.instruction & !WDAT_PRESERVE_REGISTER {
+            WDAT_WRITE_VALUE     => self.value,
+            WDAT_WRITE_COUNTDOWN => countdown,
+            _ => return,
+        };
+        let mut val = (val & self.mask) << self.bit_offset;
+
+        // Merge with the existing register contents if requested
+        if (self.instruction & WDAT_PRESERVE_REGISTER) != 0 {
+            val |= self.read() & !(self.mask << self.bit_offset);
+        }
+
+        self.write(val);
+    }
+}
+
+/// A hardware watchdog described by the ACPI WDAT table
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct WatchdogTimer {
+    /// Length of one watchdog count in milliseconds
+    pub period_ms: u32,
+
+    /// Countdown value the watchdog is reset to when pet
+    pub max_count: u32,
+
+    /// Number of valid entries in `instructions`
+    pub num_instructions: u32,
+
+    /// Instructions which reset the watchdog countdown
+    pub instructions: [WatchdogInstruction; WATCHDOG_MAX_INSTRUCTIONS],
+}
+
+impl WatchdogTimer {
+    /// Pet the watchdog, resetting its countdown
+    pub fn pet(&self) {
+        for inst in &self.instructions[..self.num_instructions as usize] {
+            unsafe { inst.execute(self.max_count); }
+        }
+    }
+}
+
+/// Network configuration learned by the PXE firmware during the boot DHCP
+/// exchange
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct PxeServerInfo {
+    /// IP address of the TFTP server we booted from
+    pub server_ip: [u8; 4],
+
+    /// IP address assigned to this machine
+    pub client_ip: [u8; 4],
+
+    /// IP address of the default gateway
+    pub gateway_ip: [u8; 4],
+
+    /// Subnet mask of the local network
+    pub subnet_mask: [u8; 4],
+
+    /// Null-terminated host name of the boot server, from the BOOTP header
+    pub server_hostname: [u8; 64],
+}
+
+/// Network configuration from the PXE boot, along with the location of the
+/// PXE UNDI network driver
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct PxeNetworkInfo {
+    /// MAC address of the NIC we booted from
+    pub client_mac: [u8; 6],
+
+    /// IP address assigned to this machine
+    pub client_ip: [u8; 4],
+
+    /// IP address of the TFTP server we booted from
+    pub server_ip: [u8; 4],
+
+    /// IP address of the default gateway
+    pub gateway: [u8; 4],
+
+    /// Subnet mask of the local network
+    pub subnet_mask: [u8; 4],
+
+    /// Physical address of the UNDI driver code
+    pub pxe_undi_base: u32,
+
+    /// Size of the UNDI driver code in bytes
+    pub pxe_undi_size: u32,
+}
+
 /// Structures to pass between both the 32-bit and 64-bit modes. This structure
 /// MUST be identical in both modes. Thus, no using pointers, references, or
 /// usizes. Also, make sure everything is marked `#[repr(C)]` otherwise the
@@ -75,7 +562,385 @@ pub struct BootArgs {
     /// unique non-overlapping stacks for cores.
     pub stack_vaddr: AtomicU64,
 
-    /// A lock to be used to make `print!()` macros fully atomic
+    /// A lock to be used to make `print!()` macros fully atomic. `LockCell`
+    /// is a ticket lock, thus CPUs printing at the same time are served in
+    /// FIFO order and can't starve each other.
     pub print_lock: LockCell<()>,
+
+    /// Number of CPUs which have yet to complete their bootloader setup. The
+    /// bootloader sets this to the number of APs in the ACPI MADT before the
+    /// BSP enters the kernel, which then launches the APs. Each AP checks in
+    /// with `cpu_checkin()` once it is done with all shared bootloader state,
+    /// and the kernel uses `wait_for_cpus()` to rendezvous with them.
+    pub cpu_barrier: AtomicU32,
+
+    /// The virtual address the initrd is mapped at in the kernel page table,
+    /// or zero if no initrd was loaded
+    pub initrd_base: AtomicU64,
+
+    /// The size of the initrd in bytes, or zero if no initrd was loaded
+    pub initrd_size: AtomicU64,
+
+    /// The virtual base in the kernel page tables where physical memory is
+    /// linearally mapped. This is chosen by the bootloader, such that the
+    /// kernel doesn't depend on being built with the same constant.
+    ///
+    /// For now the bootloader always uses `KERNEL_PHYS_WINDOW_BASE`, there is
+    /// no way to override it at runtime. An override, such as from
+    /// `boot.cfg`, would have to be checked against every other region of
+    /// the kernel address space, some of which (the kernel image and the
+    /// dynamic allocations from `KERNEL_VMEM_BASE`) have no fixed bounds.
+    pub phys_window_base: u64,
+
+    /// Physical address of an array of `MAX_APIC_IDS` `PageSlab`s indexed by
+    /// APIC ID, or zero if they have not been allocated yet
+    pub page_slabs: AtomicU64,
+
+    /// Number of CPUs which have finished in the bootloader and are entering
+    /// the kernel
+    pub online_cpus: AtomicU32,
+
+    /// APIC ID of the BSP, set by the BSP as soon as it enters the bootloader
+    pub bsp_apic_id: AtomicU32,
+
+    /// APIC IDs of every CPU which went online, indexed by the order in which
+    /// they went online. Thus the first `online_cpus` slots are used, and all
+    /// others are `0xff`. The BSP is always the first entry. A CPU takes its
+    /// slot by incrementing `online_cpus` before storing its ID, thus a used
+    /// slot may briefly still read as `0xff`.
+    pub ap_apic_ids: ApApicIds,
+
+    /// The kernel's exported symbols, if it has any
+    pub kernel_symbols: LockCell<Option<KernelSymbolTable>>,
+
+    /// The hardware watchdog, if the platform has one the bootloader can pet.
+    /// The kernel is expected to keep petting it.
+    pub watchdog: LockCell<Option<WatchdogTimer>>,
+
+    /// Set if all of the bootloader hardware self-tests passed
+    pub selftest_passed: AtomicBool,
+
+    /// Network configuration from the PXE boot, if we were able to get it
+    pub pxe_info: LockCell<Option<PxeServerInfo>>,
+
+    /// The memory map as reported by the BIOS, before any sanitization
+    pub e820: LockCell<E820Map>,
+
+    /// Set by the bootloader if a PXE transfer timed out
+    pub pxe_timeout: AtomicBool,
+
+    /// Network configuration and UNDI driver location from the PXE boot, if
+    /// we were able to get it
+    pub pxe_network_info: LockCell<Option<PxeNetworkInfo>>,
+
+    /// The package, core, and thread of every CPU which went online
+    pub cpu_topology: LockCell<CpuTopology>,
+
+    /// The virtual address of the zeroed, writable heap region mapped in the
+    /// kernel page table, or zero if it has not been mapped yet. The kernel
+    /// can hand out allocations from it without touching the page table.
+    pub heap_base: AtomicU64,
+
+    /// The size of the heap region in bytes
+    pub heap_size: AtomicU64,
+
+    /// Physical address of the PML4 of a page table which maps only the
+    /// physical memory which was free when the BSP entered the kernel, at the
+    /// addresses it has in the physical window. A page is free if its
+    /// physical window address is present in this table. Memory allocated by
+    /// APs as they come online is not reflected. Zero if not built.
+    pub physical_window_pg_table: AtomicU64,
+
+    /// Kernel entry points for each AP, indexed by APIC ID. An AP spins on
+    /// its entry until it is non-zero before entering the kernel there. The
+    /// bootloader leaves every entry zero, it is up to the kernel to release
+    /// the APs with `ApEntrySpin::release_all` once its global initialization
+    /// is done, such that APs never observe a partially initialized kernel.
+    pub ap_entry_spin: ApEntrySpin,
+
+    /// Number of bytes of physical memory which are actually mapped in the
+    /// physical window. This covers all RAM up to `KERNEL_PHYS_WINDOW_SIZE`,
+    /// anything beyond it in the window is only mapped if it was mapped
+    /// explicitly, such as with `map_mmio`.
+    pub kernel_phys_window_size: AtomicU64,
+
+    /// Number of seconds the bootloader waits after a panic before rebooting,
+    /// if it was built with the `crash-reboot` feature. This can be set with
+    /// a `reboot_delay_secs=N` line in `boot.cfg`.
+    pub reboot_delay_secs: AtomicU32,
+
+    /// Frequency of the TSC in Hz, as measured by the bootloader. This lets
+    /// the kernel bound waits in time before it has any timers of its own.
+    pub tsc_hz: AtomicU64,
+}
+
+/// Expected size of `BootArgs` in bytes. Nothing in assembly reads
+/// `BootArgs`, however it is shared between the 32-bit bootloader and the
+/// 64-bit kernel, thus any change to its layout is an ABI change. If this
+/// changes, make sure the change was intended and update the offsets in
+/// `BootArgs::check_layout`.
+const EXPECTED_SIZE: usize = 9248;
+
+/// Break the build if the size of `BootArgs` changes. This is an array length
+/// mismatch rather than an `assert!()`, such that it works in a `const`.
+const _: [(); EXPECTED_SIZE] = [(); core::mem::size_of::<BootArgs>()];
+
+/// Returns `true` if `vaddr` is a canonical 48-bit virtual address, that is,
+/// bits 63:48 are copies of bit 47
+const fn is_canonical(vaddr: u64) -> bool {
+    (((vaddr << 16) as i64) >> 16) as u64 == vaddr
 }
 
+/// Break the build if any of the fixed virtual bases are non-canonical, as
+/// nothing can be mapped there
+const _: [(); 0] = [(); !is_canonical(KERNEL_STACKS_BASE) as usize];
+const _: [(); 0] = [(); !is_canonical(KERNEL_PHYS_WINDOW_BASE) as usize];
+const _: [(); 0] = [(); !is_canonical(KERNEL_VMEM_BASE) as usize];
+const _: [(); 0] = [(); !is_canonical(KERNEL_INITRD_BASE) as usize];
+const _: [(); 0] = [(); !is_canonical(KERNEL_HEAP_BASE) as usize];
+
+/// Break the build if `BootArgs` is not `Sync`, as it is shared between all
+/// CPUs. This is checked here rather than at the use of the `static` in the
+/// bootloader, such that it is caught when building the kernel and tests too.
+const _: fn() = || {
+    fn assert_sync<T: Sync>() {}
+    assert_sync::<BootArgs>();
+};
+
+impl BootArgs {
+    /// Create a `BootArgs` with nothing initialized, matching the state of
+    /// the bootloader's `BOOT_ARGS` before anything has been set up. This
+    /// allows testing code which uses `BootArgs` outside of the bootloader,
+    /// other crates can use this with the `testing` feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_for_testing() -> BootArgs {
+        BootArgs {
+            free_memory:           LockCell::new(None),
+            serial:                LockCell::new(None),
+            page_table:            LockCell::new(None),
+            trampoline_page_table: LockCell::new(None),
+            kernel_entry:          LockCell::new(None),
+            stack_vaddr:           AtomicU64::new(KERNEL_STACKS_BASE),
+            print_lock:            LockCell::new(()),
+            cpu_barrier:           AtomicU32::new(0),
+            initrd_base:           AtomicU64::new(0),
+            initrd_size:           AtomicU64::new(0),
+            phys_window_base:      KERNEL_PHYS_WINDOW_BASE,
+            page_slabs:            AtomicU64::new(0),
+            online_cpus:           AtomicU32::new(0),
+            bsp_apic_id:           AtomicU32::new(0),
+            ap_apic_ids:           ApApicIds::new(),
+            kernel_symbols:        LockCell::new(None),
+            watchdog:              LockCell::new(None),
+            selftest_passed:       AtomicBool::new(false),
+            pxe_info:              LockCell::new(None),
+            e820:                  LockCell::new(E820Map::new()),
+            pxe_timeout:           AtomicBool::new(false),
+            pxe_network_info:      LockCell::new(None),
+            cpu_topology:          LockCell::new(CpuTopology::new()),
+            heap_base:             AtomicU64::new(0),
+            heap_size:             AtomicU64::new(0),
+            physical_window_pg_table: AtomicU64::new(0),
+            ap_entry_spin:         ApEntrySpin::new(),
+            kernel_phys_window_size: AtomicU64::new(0),
+            reboot_delay_secs:     AtomicU32::new(DEFAULT_REBOOT_DELAY_SECS),
+            tsc_hz:                AtomicU64::new(0),
+        }
+    }
+
+    /// Panic if the layout of `BootArgs` has changed. This is called by both
+    /// the bootloader and the kernel, such that the layout is checked in both
+    /// the 32-bit and 64-bit ABIs. `offset_of!()` is not available on our
+    /// toolchain, thus this can't be done in a `const` like `EXPECTED_SIZE`.
+    pub fn check_layout(&self) {
+        let base = self as *const BootArgs as usize;
+
+        macro_rules! check_offset {
+            ($field:ident, $offset:expr) => {
+                assert!(&self.$field as *const _ as usize - base == $offset,
+                        concat!("BootArgs layout changed, offset of ",
+                                stringify!($field)));
+            }
+        }
+
+        check_offset!(free_memory,           0);
+        check_offset!(serial,                536);
+        check_offset!(page_table,            568);
+        check_offset!(trampoline_page_table, 592);
+        check_offset!(kernel_entry,          616);
+        check_offset!(stack_vaddr,           640);
+        check_offset!(print_lock,            648);
+        check_offset!(cpu_barrier,           656);
+        check_offset!(initrd_base,           664);
+        check_offset!(initrd_size,           672);
+        check_offset!(phys_window_base,      680);
+        check_offset!(page_slabs,            688);
+        check_offset!(online_cpus,           696);
+        check_offset!(bsp_apic_id,           700);
+        check_offset!(ap_apic_ids,           704);
+        check_offset!(kernel_symbols,        1728);
+        check_offset!(watchdog,              1768);
+        check_offset!(selftest_passed,       1856);
+        check_offset!(pxe_info,              1860);
+        check_offset!(e820,                  1952);
+        check_offset!(pxe_timeout,           5040);
+        check_offset!(pxe_network_info,      5044);
+        check_offset!(cpu_topology,          5088);
+        check_offset!(heap_base,             7152);
+        check_offset!(heap_size,             7160);
+        check_offset!(physical_window_pg_table, 7168);
+        check_offset!(ap_entry_spin,         7176);
+        check_offset!(kernel_phys_window_size, 9224);
+        check_offset!(reboot_delay_secs,     9232);
+        check_offset!(tsc_hz,                9240);
+    }
+
+    /// Returns true if the current CPU is the BSP, by comparing its APIC ID
+    /// against `bsp_apic_id`
+    pub fn is_bsp(&self) -> bool {
+        cpu::apic_id() == self.bsp_apic_id.load(Ordering::SeqCst)
+    }
+
+    /// Get the virtual address of `paddr` in the kernel physical window
+    pub fn phys_to_virt(&self, paddr: u64) -> VirtAddr {
+        VirtAddr(self.phys_window_base) + paddr
+    }
+
+    /// Print the BIOS memory map to `serial`, in the same format as Linux
+    pub fn log_physical_memory_map(&self, serial: &mut SerialPort) {
+        use core::fmt::Write;
+
+        let e820 = self.e820.lock();
+
+        let _ = write!(serial, "BIOS-provided physical RAM map:\n");
+        for entry in e820.entries().iter().filter(|x| x.size > 0) {
+            // Get a name for the type of the region
+            let typ = match entry.typ {
+                1 => "usable",
+                2 => "reserved",
+                3 => "ACPI data",
+                4 => "ACPI NVS",
+                5 => "unusable",
+                7 => "persistent",
+                _ => "unknown",
+            };
+
+            let end = entry.base.saturating_add(entry.size - 1);
+            let _ = write!(serial, "[0x{:016X} - 0x{:016X}] {}\n",
+                           entry.base, end, typ);
+        }
+    }
+
+    /// Get the physical address of `vaddr` from the kernel physical window,
+    /// returns `None` if `vaddr` is not in the mapped part of the window
+    pub fn virt_to_phys(&self, vaddr: VirtAddr) -> Option<u64> {
+        let paddr = vaddr.offset_from(VirtAddr(self.phys_window_base))?;
+        if paddr < self.kernel_phys_window_size.load(Ordering::SeqCst) {
+            Some(paddr)
+        } else {
+            None
+        }
+    }
+
+    /// Check in at the `cpu_barrier`, marking that this CPU has completed its
+    /// bootloader setup. This will never decrement the barrier below zero.
+    pub fn cpu_checkin(&self) {
+        let mut remaining = self.cpu_barrier.load(Ordering::SeqCst);
+        while remaining != 0 {
+            match self.cpu_barrier.compare_exchange(remaining, remaining - 1,
+                    Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_)  => break,
+                Err(x) => remaining = x,
+            }
+        }
+    }
+
+    /// Spin until every CPU expected at the `cpu_barrier` has checked in, or
+    /// until `timeout_ms` milliseconds have passed. The expected count comes
+    /// from the ACPI MADT, which may list CPUs which never start, thus this
+    /// must not wait forever. Returns the number of CPUs which have not
+    /// checked in, so zero if all of them did.
+    pub fn wait_for_cpus(&self, timeout_ms: u64) -> u32 {
+        let timeout = self.tsc_hz.load(Ordering::SeqCst) / 1000 * timeout_ms;
+        let start   = cpu::rdtsc();
+
+        loop {
+            let remaining = self.cpu_barrier.load(Ordering::SeqCst);
+            if remaining == 0 ||
+                    cpu::rdtsc().wrapping_sub(start) >= timeout {
+                return remaining;
+            }
+
+            // Each AP checks in once it's done with the bootloader, which
+            // takes a while after it was started
+            cpu::pause();
+        }
+    }
+
+    /// Map `size` bytes of MMIO at physical address `paddr` into the kernel
+    /// page table as uncacheable, read-write, non-executable memory. The
+    /// mapping is created at the location this memory would have in the
+    /// physical window, and this virtual address is returned.
+    ///
+    /// Pages which are already present in the physical window (eg. MMIO which
+    /// falls inside of `KERNEL_PHYS_WINDOW_SIZE`) are made uncacheable, large
+    /// pages are split such that only the pages of the MMIO change. The TLB
+    /// is only invalidated on the current CPU.
+    pub fn map_mmio<P: PhysMem>(&self, phys_mem: &mut P, paddr: u64,
+                                size: u64) -> Option<VirtAddr> {
+        // Don't allow zero sized mappings
+        if size == 0 {
+            return None;
+        }
+
+        // Compute the 4 KiB aligned bounds of the region to map
+        let start = paddr & !0xfff;
+        let end   = paddr.checked_add(size - 1)? & !0xfff;
+
+        // Get access to the kernel page table
+        let mut page_table = self.page_table.lock();
+        let page_table = page_table.as_mut()?;
+
+        for page in (start..=end).step_by(4096) {
+            // Compute the address of this page in the physical window
+            let vaddr = self.phys_to_virt(page);
+
+            // Map in the page as uncacheable, or make the existing mapping
+            // uncacheable
+            page_table.map_uncacheable(phys_mem, vaddr, page).ok()?;
+
+            // The kernel page table is only active in 64-bit mode
+            #[cfg(target_pointer_width = "64")]
+            unsafe { cpu::invlpg(vaddr.0 as usize); }
+        }
+
+        Some(self.phys_to_virt(paddr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_phys_window() {
+        let boot_args = BootArgs::new_for_testing();
+        boot_args.kernel_phys_window_size.store(0x10000, Ordering::SeqCst);
+
+        let vaddr = boot_args.phys_to_virt(0x1337);
+        assert!(vaddr == VirtAddr(KERNEL_PHYS_WINDOW_BASE + 0x1337));
+        assert!(boot_args.virt_to_phys(vaddr) == Some(0x1337));
+
+        // Addresses outside of the mapped window don't translate
+        assert!(boot_args.virt_to_phys(VirtAddr(0x1337)).is_none());
+        assert!(boot_args.virt_to_phys(
+            boot_args.phys_to_virt(0x10000)).is_none());
+        assert!(boot_args.virt_to_phys(VirtAddr(
+            KERNEL_PHYS_WINDOW_BASE + KERNEL_PHYS_WINDOW_SIZE)).is_none());
+    }
+
+    #[test]
+    fn test_layout() {
+        BootArgs::new_for_testing().check_layout();
+    }
+}
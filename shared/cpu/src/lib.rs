@@ -29,6 +29,84 @@ pub unsafe fn in8(addr: u16) -> u8 {
     val
 }
 
+/// Output `val` to I/O port `addr`
+#[inline]
+pub unsafe fn out16(addr: u16, val: u16) {
+    asm!("out dx, ax" :: "{dx}"(addr), "{ax}"(val) :: "volatile", "intel");
+}
+
+/// Read a 16-bit value from I/O port `addr`
+#[inline]
+pub unsafe fn in16(addr: u16) -> u16 {
+    let val: u16;
+    asm!("in ax, dx" : "={ax}"(val) : "{dx}"(addr) :: "volatile", "intel");
+    val
+}
+
+/// Output `val` to I/O port `addr`
+#[inline]
+pub unsafe fn out32(addr: u16, val: u32) {
+    asm!("out dx, eax" :: "{dx}"(addr), "{eax}"(val) :: "volatile", "intel");
+}
+
+/// Read a 32-bit value from I/O port `addr`
+#[inline]
+pub unsafe fn in32(addr: u16) -> u32 {
+    let val: u32;
+    asm!("in eax, dx" : "={eax}"(val) : "{dx}"(addr) :: "volatile", "intel");
+    val
+}
+
+/// Read an 8-bit MMIO register at `addr`
+#[inline]
+pub unsafe fn mmio_read8(addr: *const u8) -> u8 {
+    core::ptr::read_volatile(addr)
+}
+
+/// Read a 16-bit MMIO register at `addr`
+#[inline]
+pub unsafe fn mmio_read16(addr: *const u16) -> u16 {
+    core::ptr::read_volatile(addr)
+}
+
+/// Read a 32-bit MMIO register at `addr`
+#[inline]
+pub unsafe fn mmio_read32(addr: *const u32) -> u32 {
+    core::ptr::read_volatile(addr)
+}
+
+/// Read a 64-bit MMIO register at `addr`. On 32-bit x86 this may be
+/// performed as two 32-bit reads.
+#[inline]
+pub unsafe fn mmio_read64(addr: *const u64) -> u64 {
+    core::ptr::read_volatile(addr)
+}
+
+/// Write `val` to the 8-bit MMIO register at `addr`
+#[inline]
+pub unsafe fn mmio_write8(addr: *mut u8, val: u8) {
+    core::ptr::write_volatile(addr, val);
+}
+
+/// Write `val` to the 16-bit MMIO register at `addr`
+#[inline]
+pub unsafe fn mmio_write16(addr: *mut u16, val: u16) {
+    core::ptr::write_volatile(addr, val);
+}
+
+/// Write `val` to the 32-bit MMIO register at `addr`
+#[inline]
+pub unsafe fn mmio_write32(addr: *mut u32, val: u32) {
+    core::ptr::write_volatile(addr, val);
+}
+
+/// Write `val` to the 64-bit MMIO register at `addr`. On 32-bit x86 this may
+/// be performed as two 32-bit writes.
+#[inline]
+pub unsafe fn mmio_write64(addr: *mut u64, val: u64) {
+    core::ptr::write_volatile(addr, val);
+}
+
 /// Invalidate a page table entry
 #[inline]
 pub unsafe fn invlpg(vaddr: usize) {
@@ -55,6 +133,88 @@ pub unsafe fn wrmsr(msr: u32, val: u64) {
          "memory" : "volatile", "intel");
 }
 
+/// Returns true if the OS has enabled `xgetbv` and `xsetbv` by setting
+/// `CR4.OSXSAVE`, as reported by CPUID leaf 1
+#[inline]
+pub fn osxsave_enabled() -> bool {
+    (unsafe { cpuid(1, 0).2 } & (1 << 27)) != 0
+}
+
+/// Read the extended control register `xcr`
+#[inline]
+pub unsafe fn xgetbv(xcr: u32) -> u64 {
+    assert!(osxsave_enabled(), "xgetbv used without OSXSAVE enabled");
+
+    let val_lo: u32;
+    let val_hi: u32;
+    asm!("xgetbv" : "={edx}"(val_hi), "={eax}"(val_lo) : "{ecx}"(xcr) :
+         "memory" : "volatile", "intel");
+    ((val_hi as u64) << 32) | val_lo as u64
+}
+
+/// Write `val` to the extended control register `xcr`
+#[inline]
+pub unsafe fn xsetbv(xcr: u32, val: u64) {
+    assert!(osxsave_enabled(), "xsetbv used without OSXSAVE enabled");
+
+    asm!("xsetbv" :: "{ecx}"(xcr), "{edx}"((val >> 32) as u32),
+         "{eax}"(val as u32) : "memory" : "volatile", "intel");
+}
+
+/// The state components enabled for XSAVE in `XCR0`
+#[derive(Clone, Copy, Debug)]
+pub struct Xcr0 {
+    /// x87 FPU state, this must always be set
+    pub x87: bool,
+
+    /// SSE state, the XMM registers and MXCSR
+    pub sse: bool,
+
+    /// AVX state, the upper halves of the YMM registers
+    pub avx: bool,
+
+    /// AVX-512 opmask registers k0-k7
+    pub opmask: bool,
+
+    /// AVX-512 upper halves of ZMM0-ZMM15
+    pub zmm_hi256: bool,
+
+    /// AVX-512 ZMM16-ZMM31
+    pub zmm_hi16: bool,
+
+    /// All other bits of `XCR0`, which are preserved as-is
+    other: u64,
+}
+
+impl Xcr0 {
+    /// Read the current value of `XCR0`
+    pub fn read() -> Xcr0 {
+        let val = unsafe { xgetbv(0) };
+
+        Xcr0 {
+            x87:       (val & (1 << 0)) != 0,
+            sse:       (val & (1 << 1)) != 0,
+            avx:       (val & (1 << 2)) != 0,
+            opmask:    (val & (1 << 5)) != 0,
+            zmm_hi256: (val & (1 << 6)) != 0,
+            zmm_hi16:  (val & (1 << 7)) != 0,
+            other:     val & !0xe7,
+        }
+    }
+
+    /// Write `val` to `XCR0`. Enabling a state component the CPU does not
+    /// support causes a #GP.
+    pub unsafe fn write(val: Xcr0) {
+        xsetbv(0, val.other |
+            ((val.x87       as u64) << 0) |
+            ((val.sse       as u64) << 1) |
+            ((val.avx       as u64) << 2) |
+            ((val.opmask    as u64) << 5) |
+            ((val.zmm_hi256 as u64) << 6) |
+            ((val.zmm_hi16  as u64) << 7));
+    }
+}
+
 /// Read the time stamp counter
 #[inline]
 pub fn rdtsc() -> u64 {
@@ -69,12 +229,70 @@ pub fn rdtsc() -> u64 {
     ((val_hi as u64) << 32) | val_lo as u64
 }
 
+/// Execute `cpuid` with `eax` set to `leaf` and `ecx` set to `subleaf`,
+/// returning (eax, ebx, ecx, edx)
+#[inline]
+pub unsafe fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+    asm!("cpuid" :
+         "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx) :
+         "{eax}"(leaf), "{ecx}"(subleaf) :: "volatile", "intel");
+    (eax, ebx, ecx, edx)
+}
+
+/// Get the initial APIC ID of the current CPU
+#[inline]
+pub fn apic_id() -> u32 {
+    unsafe { cpuid(1, 0).1 >> 24 }
+}
+
 /// Set the GS
 #[inline]
 pub unsafe fn set_gs_base(base: u64) {
     wrmsr(IA32_GS_BASE, base);
 }
 
+/// Returns true if interrupts are enabled on the current CPU
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    let flags: usize;
+
+    unsafe {
+        #[cfg(target_arch = "x86")]
+        asm!("pushfd
+              pop $0" : "=r"(flags) ::: "volatile", "intel");
+        #[cfg(target_arch = "x86_64")]
+        asm!("pushfq
+              pop $0" : "=r"(flags) ::: "volatile", "intel");
+    }
+
+    (flags & (1 << 9)) != 0
+}
+
+/// Disable interrupts on the current CPU
+#[inline]
+pub unsafe fn disable_interrupts() {
+    asm!("cli" ::: "memory" : "volatile", "intel");
+}
+
+/// Enable interrupts on the current CPU
+#[inline]
+pub unsafe fn enable_interrupts() {
+    asm!("sti" ::: "memory" : "volatile", "intel");
+}
+
+/// Hint to the CPU that we're in a spin loop. This keeps a hyperthread from
+/// starving its sibling's pipeline, and avoids the memory order violation
+/// pipeline flush when the spin loop exits. Use this in every loop which
+/// polls memory or a device, the reasons are the same for all of them.
+#[inline]
+pub fn pause() {
+    unsafe { asm!("pause" :::: "volatile", "intel"); }
+}
+
 /// Disable interrupts and halt forever
 #[inline]
 pub fn halt() -> ! {
@@ -2,11 +2,14 @@
 
 #![no_std]
 
+#[cfg(feature = "irq-save")]
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
 use core::cell::UnsafeCell;
 use core::sync::atomic::{AtomicU32, Ordering, spin_loop_hint};
 
-/// A spinlock-guarded variable
+/// A spinlock-guarded variable. The spinlock is a ticket lock, thus waiters
+/// acquire the lock in the order they started waiting for it.
 #[repr(C)]
 pub struct LockCell<T: ?Sized> {
     /// Ticket counter to get new tickets to access the `val`
@@ -14,7 +17,7 @@ pub struct LockCell<T: ?Sized> {
 
     /// Current ticket value which can be released
     release: AtomicU32,
-    
+
     /// Value which is guarded by locks
     val: UnsafeCell<T>,
 }
@@ -35,10 +38,22 @@ impl<T> LockCell<T> {
 impl<T: ?Sized> LockCell<T> {
     /// Acquire exclusive access to `self`
     pub fn lock(&self) -> LockCellGuard<T> {
+        // Check that taking this lock can't deadlock
+        #[cfg(debug_assertions)]
+        {
+            if let Some(id) = deadlock::id(self.addr()) {
+                deadlock::acquire(id);
+            }
+        }
+
         // Get a ticket
         let ticket = self.ticket.fetch_add(1, Ordering::SeqCst);
 
-        // Spin while our ticket doesn't match the release
+        // Spin while our ticket doesn't match the release, pausing such that
+        // we don't steal execution resources from the holder if it's our
+        // hyperthread sibling. `spin_loop_hint()` is a `pause` on x86, it is
+        // used rather than `cpu::pause()` such that this crate doesn't depend
+        // on `cpu`.
         while self.release.load(Ordering::SeqCst) != ticket {
             spin_loop_hint();
         }
@@ -48,6 +63,40 @@ impl<T: ?Sized> LockCell<T> {
             cell: self,
         }
     }
+
+    /// Get the address of this lock, which identifies it for deadlock
+    /// detection
+    #[cfg(debug_assertions)]
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    /// Acquire exclusive access to `self` with interrupts disabled, such that
+    /// the lock can be shared with interrupt handlers. Interrupts are
+    /// re-enabled when the guard is dropped, only if they were enabled when
+    /// the lock was acquired.
+    #[cfg(feature = "irq-save")]
+    pub fn lock_irq_save(&self) -> IrqSaveLockGuard<T> {
+        // Save the interrupt state and disable interrupts before taking the
+        // lock, such that an interrupt can never try to take the lock while
+        // we hold it
+        let interrupts = cpu::interrupts_enabled();
+        unsafe { cpu::disable_interrupts(); }
+
+        IrqSaveLockGuard {
+            guard:      ManuallyDrop::new(self.lock()),
+            interrupts: interrupts,
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<T: ?Sized> Drop for LockCell<T> {
+    fn drop(&mut self) {
+        // Forget about this lock, such that a new lock at the same address
+        // doesn't inherit its lock order
+        deadlock::forget(self.addr());
+    }
 }
 
 /// A guard structure which can implement `Drop` such that locks can be
@@ -61,6 +110,54 @@ impl<'a, T: ?Sized> Drop for LockCellGuard<'a, T> {
     fn drop(&mut self) {
         // Release the lock
         self.cell.release.fetch_add(1, Ordering::SeqCst);
+
+        #[cfg(debug_assertions)]
+        {
+            if let Some(id) = deadlock::id(self.cell.addr()) {
+                deadlock::release(id);
+            }
+        }
+    }
+}
+
+/// A guard structure from `LockCell::lock_irq_save`, which releases the lock
+/// and then restores the interrupt state when dropped
+#[cfg(feature = "irq-save")]
+pub struct IrqSaveLockGuard<'a, T: ?Sized> {
+    /// The guard for the lock, dropped manually such that the lock is always
+    /// released before interrupts are re-enabled
+    guard: ManuallyDrop<LockCellGuard<'a, T>>,
+
+    /// Set if interrupts were enabled when the lock was acquired
+    interrupts: bool,
+}
+
+#[cfg(feature = "irq-save")]
+impl<'a, T: ?Sized> Drop for IrqSaveLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // Release the lock
+        unsafe { ManuallyDrop::drop(&mut self.guard); }
+
+        // Restore the interrupt state
+        if self.interrupts {
+            unsafe { cpu::enable_interrupts(); }
+        }
+    }
+}
+
+#[cfg(feature = "irq-save")]
+impl<'a, T: ?Sized> Deref for IrqSaveLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+#[cfg(feature = "irq-save")]
+impl<'a, T: ?Sized> DerefMut for IrqSaveLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
     }
 }
 
@@ -82,6 +179,183 @@ impl<'a, T: ?Sized> DerefMut for LockCellGuard<'a, T> {
     }
 }
 
+/// Deadlock detection for debug builds. Every lock is given an ID the first
+/// time it is used, and when a lock is acquired while others are held on the
+/// same CPU, an edge from each held lock to the new lock is recorded in a lock
+/// order graph. If a lock being acquired can already reach one of the held
+/// locks in the graph, the locks have been taken in inconsistent orders, which
+/// can deadlock.
+///
+/// IDs are keyed by the address of the lock and kept in a table private to
+/// each binary, rather than being stored in the lock. Locks shared between
+/// the bootloader and the kernel, such as those in `BootArgs`, are thus
+/// tracked independently by each of them.
+#[cfg(debug_assertions)]
+mod deadlock {
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+    #[cfg(test)]
+    use core::sync::atomic::AtomicU32;
+
+    /// Maximum number of locks which are tracked, any locks beyond this are
+    /// not checked for deadlocks
+    const MAX_LOCKS: usize = 64;
+
+    /// Maximum number of CPUs which locks can be tracked on
+    const MAX_CPUS: usize = 256;
+
+    /// A table of bitmasks. These are accessed as `AtomicU64`s, but stored as
+    /// `u64`s as arrays of atomics cannot be constructed in a `static`.
+    struct Bitmasks<T>(UnsafeCell<T>);
+    unsafe impl<T> Sync for Bitmasks<T> {}
+
+    /// The lock order graph, bit `n` of `GRAPH[m]` is set if lock `n` has been
+    /// acquired while holding lock `m`
+    static GRAPH: Bitmasks<[u64; MAX_LOCKS]> =
+        Bitmasks(UnsafeCell::new([0; MAX_LOCKS]));
+
+    /// The locks held by each CPU, bit `n` of `HELD[cpu]` is set if lock `n`
+    /// is currently held by `cpu`
+    static HELD: Bitmasks<[u64; MAX_CPUS]> =
+        Bitmasks(UnsafeCell::new([0; MAX_CPUS]));
+
+    /// A table of lock addresses. These are accessed as `AtomicUsize`s, but
+    /// stored as `usize`s for the same reason as `Bitmasks`.
+    struct Addresses(UnsafeCell<[usize; MAX_LOCKS]>);
+    unsafe impl Sync for Addresses {}
+
+    /// The address of the lock with ID `n` is in `LOCKS[n]`, zero if the ID
+    /// has not been assigned to a lock yet
+    static LOCKS: Addresses = Addresses(UnsafeCell::new([0; MAX_LOCKS]));
+
+    /// Get the `idx`th bitmask from a `Bitmasks` table
+    fn bitmask<T>(table: &'static Bitmasks<T>, idx: usize)
+            -> &'static AtomicU64 {
+        unsafe { &*(table.0.get() as *const AtomicU64).add(idx) }
+    }
+
+    /// Get the `idx`th slot of `LOCKS`
+    fn lock_slot(idx: usize) -> &'static AtomicUsize {
+        unsafe { &*(LOCKS.0.get() as *const AtomicUsize).add(idx) }
+    }
+
+    /// Get a unique index for the current CPU
+    #[cfg(not(test))]
+    #[allow(unused_unsafe)]
+    fn cpu_index() -> usize {
+        #[cfg(target_arch = "x86")]
+        use core::arch::x86::__cpuid;
+        #[cfg(target_arch = "x86_64")]
+        use core::arch::x86_64::__cpuid;
+
+        // Use the initial APIC ID
+        (unsafe { __cpuid(1).ebx } >> 24) as usize % MAX_CPUS
+    }
+
+    /// Get a unique index for the current thread, as test threads migrate
+    /// between CPUs
+    #[cfg(test)]
+    fn cpu_index() -> usize {
+        extern crate std;
+
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        std::thread_local! {
+            static INDEX: usize =
+                NEXT.fetch_add(1, Ordering::SeqCst) as usize % MAX_CPUS;
+        }
+
+        INDEX.with(|x| *x)
+    }
+
+    /// Get the ID of the lock at `addr`, assigning it an ID if it does not
+    /// have one yet. Returns `None` if the lock is not tracked.
+    pub fn id(addr: usize) -> Option<usize> {
+        for idx in 0..MAX_LOCKS {
+            let slot = lock_slot(idx);
+
+            // Claim the first free slot if the lock isn't in the table,
+            // someone else may have beat us to it
+            let cur = match slot.compare_exchange(0, addr, Ordering::SeqCst,
+                                                  Ordering::SeqCst) {
+                Ok(_)    => addr,
+                Err(cur) => cur,
+            };
+
+            if cur == addr {
+                return Some(idx);
+            }
+        }
+
+        None
+    }
+
+    /// Remove the lock at `addr` from the lock order graph, if it is tracked,
+    /// and free its ID
+    pub fn forget(addr: usize) {
+        let idx = match (0..MAX_LOCKS)
+                .find(|&idx| lock_slot(idx).load(Ordering::SeqCst) == addr) {
+            Some(idx) => idx,
+            None      => return,
+        };
+
+        // Remove all edges to and from the lock before anyone else can be
+        // given its ID
+        bitmask(&GRAPH, idx).store(0, Ordering::SeqCst);
+        for ii in 0..MAX_LOCKS {
+            bitmask(&GRAPH, ii).fetch_and(!(1 << idx), Ordering::SeqCst);
+        }
+        lock_slot(idx).store(0, Ordering::SeqCst);
+    }
+
+    /// Record that the lock `id` is about to be acquired on this CPU, panics
+    /// if acquiring it could deadlock
+    pub fn acquire(id: usize) {
+        let held_mask = bitmask(&HELD, cpu_index());
+        let held      = held_mask.load(Ordering::SeqCst);
+
+        // Taking a lock we already hold will never make progress
+        if (held & (1 << id)) != 0 {
+            held_mask.store(0, Ordering::SeqCst);
+            panic!("Deadlock: lock {} acquired while already held", id);
+        }
+
+        // Find all locks which have been acquired after this one, directly
+        // or indirectly
+        let mut reach = bitmask(&GRAPH, id).load(Ordering::SeqCst);
+        loop {
+            let mut next = reach;
+            for ii in (0..MAX_LOCKS).filter(|ii| (reach & (1 << ii)) != 0) {
+                next |= bitmask(&GRAPH, ii).load(Ordering::SeqCst);
+            }
+
+            if next == reach {
+                break;
+            }
+            reach = next;
+        }
+
+        // If any of those are held, the lock order has been inverted. Forget
+        // the held locks such that the panic handler can still take locks.
+        if (reach & held) != 0 {
+            held_mask.store(0, Ordering::SeqCst);
+            panic!("Potential deadlock: lock {} acquired while holding locks \
+                    {:#x}, which have previously been acquired after it",
+                   id, reach & held);
+        }
+
+        // Record the lock order and mark the lock as held
+        for ii in (0..MAX_LOCKS).filter(|ii| (held & (1 << ii)) != 0) {
+            bitmask(&GRAPH, ii).fetch_or(1 << id, Ordering::SeqCst);
+        }
+        held_mask.fetch_or(1 << id, Ordering::SeqCst);
+    }
+
+    /// Record that the lock `id` has been released on this CPU
+    pub fn release(id: usize) {
+        bitmask(&HELD, cpu_index()).fetch_and(!(1 << id), Ordering::SeqCst);
+    }
+}
+
 #[cfg(test)]
 mod test {
     extern crate std;
@@ -115,6 +389,39 @@ mod test {
         let _var = LockCell::new(Foo);
         let _lk  = _var.lock();
     }
-}
 
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_lock_order_inversion() {
+        let a = LockCell::new(());
+        let b = LockCell::new(());
+
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+
+        let _b = b.lock();
+        let _a = a.lock();
+    }
 
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_lock_order_forgotten_on_drop() {
+        // Locks which end up at the address of dropped locks must not
+        // inherit the lock order of the dropped locks
+        for inverted in &[false, true] {
+            let a = LockCell::new(());
+            let b = LockCell::new(());
+
+            if *inverted {
+                let _b = b.lock();
+                let _a = a.lock();
+            } else {
+                let _a = a.lock();
+                let _b = b.lock();
+            }
+        }
+    }
+}
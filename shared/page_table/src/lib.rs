@@ -4,13 +4,60 @@
 
 use core::alloc::Layout;
 use core::mem::size_of;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 pub const PAGE_PRESENT: u64 = 1 <<  0;
 pub const PAGE_WRITE:   u64 = 1 <<  1;
 pub const PAGE_USER:    u64 = 1 <<  2;
+pub const PAGE_PWT:     u64 = 1 <<  3;
+pub const PAGE_PCD:     u64 = 1 <<  4;
 pub const PAGE_SIZE:    u64 = 1 <<  7;
 pub const PAGE_NX:      u64 = 1 << 63;
 
+/// Software bit marking a non-present entry as a guard page, which is never
+/// allowed to be mapped over
+pub const PAGE_GUARD:   u64 = 1 <<  9;
+
+/// Page table bits which select strong uncacheable (UC) memory with the
+/// default PAT configuration
+pub const PAGE_CACHE_UC: u64 = PAGE_PCD | PAGE_PWT;
+
+/// Errors which can occur while operating on a page table
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageTableError {
+    /// The requested range was empty or overflowed the address space
+    InvalidRange,
+
+    /// The virtual address is not mapped in the page table
+    NotMapped(VirtAddr),
+
+    /// The virtual address is not canonical
+    NonCanonical(VirtAddr),
+
+    /// The raw page table entry is not present, or is missing the page size
+    /// bit for a large page
+    InvalidEntry,
+
+    /// The virtual address is already covered by a mapping or table of a
+    /// different page size
+    SizeConflict(VirtAddr),
+
+    /// The virtual address is a guard page
+    GuardPageViolation(VirtAddr),
+
+    /// The virtual address is not aligned to `align`, the page size of the
+    /// mapping
+    MisalignedAddress { vaddr: VirtAddr, align: u64 },
+
+    /// The virtual address is already mapped, and mappings are never replaced
+    /// by `map`
+    DuplicateMapping(VirtAddr),
+
+    /// Physical memory for a page or a table could not be allocated
+    OutOfMemory,
+}
+
 /// The state of a page table mapping. Contains the information about every
 /// level of the translation. Also contains information about whether the
 /// page is final
@@ -68,21 +115,101 @@ pub struct PhysAddr(pub u64);
 #[repr(C)]
 pub struct VirtAddr(pub u64);
 
+impl VirtAddr {
+    /// Create a `VirtAddr` from `addr`, returning `None` if it is not
+    /// canonical
+    pub fn canonical_or_none(addr: u64) -> Option<VirtAddr> {
+        Some(VirtAddr(addr)).filter(|x| x.is_canonical())
+    }
+
+    /// Returns `true` if bits 63:48 of the address are all copies of bit 47,
+    /// as x86-64 requires
+    pub fn is_canonical(self) -> bool {
+        cpu::canonicalize_address(self.0) == self.0
+    }
+
+    /// Add `rhs` bytes to the address, returning `None` on overflow
+    pub fn checked_add(self, rhs: u64) -> Option<VirtAddr> {
+        self.0.checked_add(rhs).map(VirtAddr)
+    }
+
+    /// Add `rhs` bytes to the address, saturating at the top of the address
+    /// space
+    pub fn saturating_add(self, rhs: u64) -> VirtAddr {
+        VirtAddr(self.0.saturating_add(rhs))
+    }
+
+    /// Get the number of bytes `self` is past `base`, returning `None` if
+    /// `self` is below `base`
+    pub fn offset_from(self, base: VirtAddr) -> Option<u64> {
+        self.0.checked_sub(base.0)
+    }
+}
+
+impl Add<u64> for VirtAddr {
+    type Output = VirtAddr;
+
+    /// Add `rhs` bytes to the address, panicking on overflow in debug builds
+    fn add(self, rhs: u64) -> VirtAddr {
+        if cfg!(debug_assertions) {
+            self.checked_add(rhs).expect("Integer overflow on VirtAddr add")
+        } else {
+            VirtAddr(self.0.wrapping_add(rhs))
+        }
+    }
+}
+
+impl Sub<u64> for VirtAddr {
+    type Output = VirtAddr;
+
+    /// Subtract `rhs` bytes from the address, panicking on underflow in debug
+    /// builds
+    fn sub(self, rhs: u64) -> VirtAddr {
+        if cfg!(debug_assertions) {
+            VirtAddr(self.0.checked_sub(rhs)
+                .expect("Integer underflow on VirtAddr sub"))
+        } else {
+            VirtAddr(self.0.wrapping_sub(rhs))
+        }
+    }
+}
+
+impl Sub<VirtAddr> for VirtAddr {
+    type Output = u64;
+
+    /// Get the number of bytes between two addresses, panicking if `rhs` is
+    /// above `self` in debug builds
+    fn sub(self, rhs: VirtAddr) -> u64 {
+        if cfg!(debug_assertions) {
+            self.offset_from(rhs).expect("Integer underflow on VirtAddr sub")
+        } else {
+            self.0.wrapping_sub(rhs.0)
+        }
+    }
+}
+
 pub trait PhysMem {
     /// Provide a virtual address to memory which contains the raw physical
     /// memory at `paddr` for `size` bytes
     unsafe fn translate(&mut self, paddr: PhysAddr, size: usize) -> *mut u8;
    
-    /// Allocate physical memory with a requested layout
-    fn alloc_phys(&mut self, layout: Layout) -> PhysAddr;
+    /// Allocate physical memory with a requested layout, returns `None` if
+    /// the allocator is out of memory
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr>;
 
     /// Free physical memory
     fn free_phys(&mut self, paddr: PhysAddr, size: u64);
 
+    /// Returns true if `paddr` is backed by usable RAM. This is only used for
+    /// sanity checks, and by default all addresses are assumed to be RAM.
+    fn is_valid_ram(&mut self, _paddr: PhysAddr) -> bool {
+        true
+    }
+
     /// Same as `alloc_phys` but the memory will be zeroed
-    fn alloc_phys_zeroed(&mut self, layout: Layout) -> PhysAddr {
+    fn alloc_phys_zeroed(&mut self, layout: Layout) -> Option<PhysAddr> {
         // Create an allocation
-        let alc = self.alloc_phys(layout);
+        let alc = self.alloc_phys(layout)?;
 
         // Zero it out
         unsafe {
@@ -90,19 +217,73 @@ pub trait PhysMem {
             core::ptr::write_bytes(bytes, 0, layout.size());
         }
 
-        alc
+        Some(alc)
     }
 }
 
-/// Different page sizes for 4-level x86_64 paging
+/// Different page sizes for 4-level x86_64 paging, ordered from smallest to
+/// largest
 #[repr(u64)]
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum PageType {
     Page4K = 4096,
     Page2M = 2 * 1024 * 1024,
     Page1G = 1 * 1024 * 1024 * 1024,
 }
 
+/// Get the largest page size which can be used to map `paddr` at `vaddr`,
+/// such that both addresses are aligned to the page size and the page is no
+/// larger than the `remaining` bytes to map. Falls back to `Page4K` if no
+/// page size fits.
+pub fn largest_fitting_page(vaddr: VirtAddr, paddr: u64, remaining: u64)
+        -> PageType {
+    for &page_type in &[PageType::Page1G, PageType::Page2M] {
+        let size = page_type as u64;
+        if (vaddr.0 & (size - 1)) == 0 && (paddr & (size - 1)) == 0 &&
+                remaining >= size {
+            return page_type;
+        }
+    }
+
+    PageType::Page4K
+}
+
+/// Counts of the tables in use by a `PageTable`, from `PageTable::stats`
+#[derive(Clone, Copy, Default)]
+pub struct PageTableStats {
+    /// Number of present entries in the PML4
+    pub pml4_entries: u64,
+
+    /// Number of page directory pointer tables
+    pub pdpt_nodes: u64,
+
+    /// Number of page directories
+    pub pd_nodes: u64,
+
+    /// Number of page tables
+    pub pt_nodes: u64,
+}
+
+impl PageTableStats {
+    /// Number of bytes of physical memory used by the tables
+    pub fn memory_usage(&self) -> u64 {
+        // All the tables, including the PML4, are 4 KiB each
+        (1 + self.pdpt_nodes + self.pd_nodes + self.pt_nodes) * 4096
+    }
+}
+
+impl core::fmt::Debug for PageTableStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("PageTable")
+            .field("pml4_entries",       &self.pml4_entries)
+            .field("pdpt_nodes",         &self.pdpt_nodes)
+            .field("pd_nodes",           &self.pd_nodes)
+            .field("pt_nodes",           &self.pt_nodes)
+            .field("total_pt_memory_kb", &(self.memory_usage() / 1024))
+            .finish()
+    }
+}
+
 /// A 64-bit x86 page table 
 #[repr(C)]
 pub struct PageTable {
@@ -111,16 +292,28 @@ pub struct PageTable {
     table: PhysAddr,
 }
 
+impl core::fmt::Debug for PageTable {
+    /// Only the root table address is shown, as displaying the table contents
+    /// requires access to physical memory, use `PageTable::stats` for that
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("PageTable")
+            .field("table", &self.table)
+            .finish()
+    }
+}
+
 impl PageTable {
     /// Create a new empty page table
-    pub fn new<P: PhysMem>(phys_mem: &mut P) -> PageTable {
+    pub fn new<P: PhysMem>(phys_mem: &mut P)
+            -> Result<PageTable, PageTableError> {
         // Allocate the root level table
         let table = phys_mem.alloc_phys_zeroed(
-            Layout::from_size_align(4096, 4096).unwrap());
+            Layout::from_size_align(4096, 4096).unwrap())
+            .ok_or(PageTableError::OutOfMemory)?;
 
-        PageTable {
+        Ok(PageTable {
             table,
-        }
+        })
     }
 
     /// Get the address of the page table
@@ -128,12 +321,116 @@ impl PageTable {
         self.table
     }
 
+    /// Get the value to load into `cr3` to use this page table tagged with
+    /// the 12-bit process-context identifier `pcid`. The PCID is only used
+    /// by the CPU if `CR4.PCIDE` is set, and must be 0 otherwise.
+    pub fn table_with_pcid(&self, pcid: u16) -> u64 {
+        assert!(pcid < 4096, "PCID must be 12 bits");
+        self.table.0 | pcid as u64
+    }
+
+    /// Count the entries and tables in use at each level of the page table.
+    /// This walks every table, thus it is expensive and meant for debugging.
+    pub fn stats<P: PhysMem>(&self, phys_mem: &mut P) -> PageTableStats {
+        let mut stats = PageTableStats::default();
+
+        // Read the `idx`th entry of `table`, returning the address of the
+        // next level table it points to, if it does
+        let mut next_table = |table: PhysAddr, idx: u64| {
+            let ent = unsafe {
+                core::ptr::read(phys_mem.translate(
+                    PhysAddr(table.0 + idx * size_of::<u64>() as u64),
+                    size_of::<u64>()) as *const u64)
+            };
+
+            if (ent & PAGE_PRESENT) != 0 && (ent & PAGE_SIZE) == 0 {
+                Some(PhysAddr(ent & 0xffffffffff000))
+            } else {
+                None
+            }
+        };
+
+        // Walk the PML4
+        for pml4e in 0..512 {
+            let pdpt = match next_table(self.table, pml4e) {
+                Some(pdpt) => pdpt,
+                None       => continue,
+            };
+            stats.pml4_entries += 1;
+            stats.pdpt_nodes   += 1;
+
+            // Walk the PDPT, skipping 1 GiB pages
+            for pdpe in 0..512 {
+                let pd = match next_table(pdpt, pdpe) {
+                    Some(pd) => pd,
+                    None     => continue,
+                };
+                stats.pd_nodes += 1;
+
+                // Walk the PD, skipping 2 MiB pages
+                for pde in 0..512 {
+                    if next_table(pd, pde).is_some() {
+                        stats.pt_nodes += 1;
+                    }
+                }
+            }
+        }
+
+        stats
+    }
+
+    /// Get the number of bytes of physical memory used by the tables
+    /// themselves, rather than the pages they map. This walks every table,
+    /// see `stats`.
+    pub fn memory_usage<P: PhysMem>(&self, phys_mem: &mut P) -> u64 {
+        self.stats(phys_mem).memory_usage()
+    }
+
+    /// Make sure `size` bytes starting at `vaddr` is a non-empty range which
+    /// does not wrap around, and which is entirely in either the lower or the
+    /// upper canonical half of the address space
+    fn check_range(vaddr: VirtAddr, size: u64) -> Result<(), PageTableError> {
+        let end = size.checked_sub(1).and_then(|x| vaddr.0.checked_add(x))
+            .ok_or(PageTableError::InvalidRange)?;
+
+        if !vaddr.is_canonical() {
+            return Err(PageTableError::NonCanonical(vaddr));
+        }
+        if !VirtAddr(end).is_canonical() {
+            return Err(PageTableError::NonCanonical(VirtAddr(end)));
+        }
+
+        // Both ends are canonical, thus the range stays in one half unless
+        // it spans the whole non-canonical hole
+        if (vaddr.0 >> 63) != (end >> 63) {
+            return Err(PageTableError::InvalidRange);
+        }
+
+        Ok(())
+    }
+
+    /// Same as `map`, however the range is always validated to not wrap
+    /// around and to stay within a single canonical half of the address
+    /// space, rather than only checking the start in debug builds. Use this
+    /// for any range which comes from untrusted input.
+    pub fn map_range_checked<P: PhysMem>(&mut self,
+            phys_mem: &mut P, vaddr: VirtAddr, page_type: PageType,
+            size: u64, read: bool, write: bool, exec: bool)
+            -> Result<(), PageTableError> {
+        Self::check_range(vaddr, size)?;
+        self.map(phys_mem, vaddr, page_type, size, read, write, exec)
+    }
+
     /// Create a page table entry at `vaddr` for `size` bytes in length,
     /// `page_type` as the page size. `read`, `write`, and `exec` will be used
     /// as the permission bits.
     pub fn map<P: PhysMem>(&mut self, 
             phys_mem: &mut P, vaddr: VirtAddr, page_type: PageType,
-            size: u64, read: bool, write: bool, exec: bool) -> Option<()> {
+            size: u64, read: bool, write: bool, exec: bool)
+            -> Result<(), PageTableError> {
+        debug_assert!(vaddr.is_canonical(),
+            "Non-canonical address passed to map: {:#x}", vaddr.0);
+
         self.map_init(phys_mem,
             vaddr, page_type, size, read, write, exec, None::<fn(u64) -> u8>)
     }
@@ -143,8 +440,8 @@ impl PageTable {
     /// as the permission bits.
     ///
     /// If the virtual memory is already mapped or the virtual address at any
-    /// point over the range is non-canonical, this will return `None` and the
-    /// page table will not be modified.
+    /// point over the range is non-canonical, this will return an error and
+    /// the page table will not be modified.
     ///
     /// If `init` is `Some`, it will be invoked with the current offset into
     /// the mapping, and the return value from the closure will be used to
@@ -152,9 +449,77 @@ impl PageTable {
     pub fn map_init<F, P: PhysMem>(
                 &mut self, phys_mem: &mut P,
                 vaddr: VirtAddr, page_type: PageType,
-                size: u64, _read: bool, write: bool, exec: bool,
-                init: Option<F>) -> Option<()>
+                size: u64, read: bool, write: bool, exec: bool,
+                init: Option<F>) -> Result<(), PageTableError>
             where F: Fn(u64) -> u8 {
+        self.map_pages(phys_mem, vaddr, page_type, size, read, write, exec,
+            init.map(|init| move |off: u64, page: &mut [u8]| {
+                for (ii, byte) in page.iter_mut().enumerate() {
+                    *byte = init(off + ii as u64);
+                }
+            }))
+    }
+
+    /// Create a page table entry at `vaddr` for `data.len() + zero_fill`
+    /// bytes in length, `page_type` as the page size. `read`, `write`, and
+    /// `exec` will be used as the permission bits.
+    ///
+    /// The mapping is initialized by copying in `data` directly, and all
+    /// remaining bytes in the mapping are zeroed. This behaves the same as
+    /// `map_init` otherwise, but avoids invoking a closure for every byte.
+    ///
+    /// As this is used to load images, the range is validated the same as
+    /// `map_range_checked`.
+    pub fn map_init_slice<P: PhysMem>(
+                &mut self, phys_mem: &mut P,
+                vaddr: VirtAddr, page_type: PageType,
+                data: &[u8], zero_fill: u64,
+                read: bool, write: bool, exec: bool)
+                -> Result<(), PageTableError> {
+        // Compute the total size of the mapping
+        let size = (data.len() as u64).checked_add(zero_fill)
+            .ok_or(PageTableError::InvalidRange)?;
+        Self::check_range(vaddr, size)?;
+
+        // Initialize each page from `data`, zero filling anything beyond it
+        let init = |off: u64, page: &mut [u8]| {
+            // Determine the number of bytes in this page which come from
+            // `data`
+            let to_copy = core::cmp::min(
+                (data.len() as u64).saturating_sub(off),
+                page.len() as u64) as usize;
+
+            unsafe {
+                if to_copy > 0 {
+                    // Copy in the initialized bytes
+                    core::ptr::copy_nonoverlapping(
+                        data.as_ptr().add(off as usize), page.as_mut_ptr(),
+                        to_copy);
+                }
+
+                // Zero out the rest of the page
+                core::ptr::write_bytes(page.as_mut_ptr().add(to_copy), 0,
+                                       page.len() - to_copy);
+            }
+        };
+
+        self.map_pages(phys_mem, vaddr, page_type, size, read, write, exec,
+                       Some(init))
+    }
+
+    /// Create a page table entry at `vaddr` for `size` bytes in length,
+    /// `page_type` as the page size. `read`, `write`, and `exec` will be used
+    /// as the permission bits.
+    ///
+    /// If `init` is `Some`, it will be invoked for each newly allocated page
+    /// with the offset of the page into the mapping and the contents of the
+    /// page, such that the page can be initialized.
+    fn map_pages<F, P: PhysMem>(
+                &mut self, phys_mem: &mut P,
+                vaddr: VirtAddr, page_type: PageType,
+                size: u64, _read: bool, write: bool, exec: bool,
+                mut init: Option<F>) -> Result<(), PageTableError>
+            where F: FnMut(u64, &mut [u8]) {
         // Get the raw page size in bytes and the mask
         let page_size = page_type as u64;
         let page_mask = page_size - 1;
@@ -164,12 +529,19 @@ impl PageTable {
 
         // Make sure that the virtual address is aligned to the page size
         // request
-        if size <= 0 || (vaddr.0 & page_mask) != 0 {
-            return None;
+        if size <= 0 {
+            return Err(PageTableError::InvalidRange);
+        }
+        if (vaddr.0 & page_mask) != 0 {
+            return Err(PageTableError::MisalignedAddress {
+                vaddr: vaddr,
+                align: page_size,
+            });
         }
 
         // Compute the end virtual address of this mapping
-        let end_vaddr = vaddr.0.checked_add(size - 1)?;
+        let end_vaddr = vaddr.0.checked_add(size - 1)
+            .ok_or(PageTableError::InvalidRange)?;
 
         // Go through each page in this mapping
         for vaddr in (vaddr.0..=end_vaddr).step_by(page_size as usize) {
@@ -177,6 +549,26 @@ impl PageTable {
             let page = phys_mem.alloc_phys(
                 Layout::from_size_align(page_size as usize,
                                         page_size as usize).unwrap());
+            let page = match page {
+                Some(page) => page,
+                None => {
+                    // Out of memory, undo everything we have done so far
+                    let mapped = vaddr - orig_vaddr.0;
+                    if mapped > 0 {
+                        unsafe {
+                            self.free(phys_mem, orig_vaddr, mapped)
+                                .expect("Failed to free what we just mapped");
+                        }
+                    }
+
+                    return Err(PageTableError::OutOfMemory);
+                }
+            };
+
+            // Make sure the allocator gave us actual RAM, and not something
+            // like MMIO
+            debug_assert!(phys_mem.is_valid_ram(page),
+                "Attempted to map a page which is not usable RAM");
 
             // Create the page table entry for this page
             let ent = page.0 | PAGE_PRESENT |
@@ -184,7 +576,7 @@ impl PageTable {
                 if exec  { 0 } else { PAGE_NX } |
                 if page_type != PageType::Page4K { PAGE_SIZE } else { 0 };
 
-            if let Some(init) = &init {
+            if let Some(init) = &mut init {
                 // Translate the page
                 let sliced = unsafe {
                     let bytes = phys_mem.translate(page, page_size as usize);
@@ -194,15 +586,23 @@ impl PageTable {
                         bytes, page_size as usize)
                 };
 
-                for (off, byte) in sliced.iter_mut().enumerate() {
-                    *byte = init(vaddr - orig_vaddr.0 + off as u64);
-                }
+                // Initialize the page
+                init(vaddr - orig_vaddr.0, sliced);
             }
 
-            // Add this mapping to the page table
+            // Add this mapping to the page table, making sure we never
+            // replace an existing mapping
+            let result = match self.translate(phys_mem, VirtAddr(vaddr)) {
+                None => Err(PageTableError::NonCanonical(VirtAddr(vaddr))),
+                Some(mapping) if mapping.page.is_some() =>
+                    Err(PageTableError::DuplicateMapping(VirtAddr(vaddr))),
+                Some(_) => unsafe {
+                    self.map_raw(phys_mem, VirtAddr(vaddr), page_type, ent)
+                },
+            };
+
             unsafe {
-                if self.map_raw(phys_mem, VirtAddr(vaddr),
-                        page_type, ent).is_none() {
+                if let Err(err) = result {
                     // Failed to map, undo everything we have done so far
                     let mapped = vaddr - orig_vaddr.0;
 
@@ -212,12 +612,125 @@ impl PageTable {
                             .expect("Failed to free what we just mapped");
                     }
 
-                    return None;
+                    return Err(err);
                 }
             }
         }
 
-        Some(())
+        Ok(())
+    }
+
+    /// Check that the region at `vaddr` for `vsize` bytes is mapped and was
+    /// initialized correctly. The first byte of the region in each page is
+    /// compared against `expected_byte`, which is invoked with the offset
+    /// into the region, the same as the closure passed to `map_init`.
+    ///
+    /// Returns `false` if any page is not mapped or has unexpected contents.
+    pub fn verify_sections<F, P>(&mut self, phys_mem: &mut P,
+            vaddr: VirtAddr, vsize: u64, mut expected_byte: F) -> bool
+            where F: FnMut(u64) -> u8,
+                  P: PhysMem {
+        // Nothing to verify
+        if vsize == 0 {
+            return true;
+        }
+
+        // Determine the end of the region
+        let end = match vaddr.checked_add(vsize - 1) {
+            Some(end) => end,
+            None      => return false,
+        };
+
+        let mut cur = vaddr;
+        loop {
+            // Get the page backing the current address
+            let mapping = self.translate(phys_mem, cur);
+            let (page, offset) = match mapping.and_then(|x| x.page) {
+                Some(page) => page,
+                None       => return false,
+            };
+
+            // Compare the byte
+            let byte = unsafe {
+                *phys_mem.translate(PhysAddr(page.0 + offset), 1)
+            };
+            if byte != expected_byte(cur - vaddr) {
+                return false;
+            }
+
+            // Go to the next page, stopping at the end of the range
+            let mapping = mapping.unwrap();
+            let next = mapping.virt_base().unwrap()
+                .checked_add(mapping.size().unwrap() as u64);
+            match next {
+                Some(next) if next <= end => cur = next,
+                _ => break,
+            }
+        }
+
+        true
+    }
+
+    /// Change the permissions of all pages in the already mapped region at
+    /// `vaddr` for `size` bytes to `read`, `write`, and `exec`, without
+    /// changing the pages backing the mapping.
+    ///
+    /// If any page in the range is not mapped, the page table is not modified.
+    /// The caller is responsible for invalidating the TLB for the range.
+    pub fn remap_with_flags<P: PhysMem>(&mut self, phys_mem: &mut P,
+            vaddr: VirtAddr, size: u64, _read: bool, write: bool, exec: bool)
+            -> Result<(), PageTableError> {
+        // Determine the end of the region
+        let end = size.checked_sub(1).and_then(|x| x.checked_add(vaddr.0))
+            .ok_or(PageTableError::InvalidRange)?;
+
+        // We go through the memory range twice. The first time we validate
+        // that all virtual memory in the range is mapped, the second time we
+        // actually update the permissions.
+        for &validate in &[true, false] {
+            let mut cur = vaddr.0;
+
+            loop {
+                // Get the mapping for the current page
+                let mapping = self.translate(phys_mem, VirtAddr(cur))
+                    .ok_or(PageTableError::NotMapped(VirtAddr(cur)))?;
+                let page_type = mapping.size()
+                    .ok_or(PageTableError::NotMapped(VirtAddr(cur)))?;
+
+                if !validate {
+                    // Get the final entry of the translation
+                    let entry = match page_type {
+                        PageType::Page4K => mapping.pte,
+                        PageType::Page2M => mapping.pde,
+                        PageType::Page1G => mapping.pdpe,
+                    }.unwrap();
+
+                    unsafe {
+                        // Get access to the entry
+                        let entry = &*(phys_mem.translate(entry,
+                            size_of::<u64>()) as *const AtomicU64);
+
+                        // Clear the old permissions and set the new ones
+                        entry.fetch_and(!(PAGE_WRITE | PAGE_NX),
+                                        Ordering::SeqCst);
+                        entry.fetch_or(
+                            if write { PAGE_WRITE } else { 0 } |
+                            if exec  { 0 } else { PAGE_NX },
+                            Ordering::SeqCst);
+                    }
+                }
+
+                // Go to the next page, stopping at the end of the range
+                let next = mapping.virt_base().unwrap().0
+                    .checked_add(page_type as u64);
+                match next {
+                    Some(next) if next <= end => cur = next,
+                    _ => break,
+                }
+            }
+        }
+
+        Ok(())
     }
 
     /// Free the virtual memory region indicated by `vaddr` and `size`. All
@@ -356,6 +869,19 @@ impl PageTable {
         Some(())
     }
 
+    /// Iterate over every present leaf entry in the page table, yielding the
+    /// virtual address, the raw page table entry, and the page size of each
+    /// mapping in ascending virtual address order
+    pub fn iter_present_leaves<'a, P: PhysMem>(&'a self, phys_mem: &'a mut P)
+            -> impl Iterator<Item = (VirtAddr, u64, PageType)> + 'a {
+        PresentLeaves {
+            phys_mem: phys_mem,
+            tables:   [self.table, PhysAddr(0), PhysAddr(0), PhysAddr(0)],
+            indicies: [0; 4],
+            depth:    0,
+        }
+    }
+
     /// Translate a virtual address in the `self` page table into its
     /// components. This will include entries for every level in the table as
     /// well as the final page result if the page is mapped and present.
@@ -444,33 +970,222 @@ impl PageTable {
         Some(ret)
     }
 
+    /// Insert a guard page at `vaddr`, which must be 4 KiB aligned and not
+    /// already mapped. The guard page is a non-present entry marked with
+    /// `PAGE_GUARD`, thus any access to it faults, and any later attempt to
+    /// map over it with `map_raw` or `map` fails with
+    /// `PageTableError::GuardPageViolation`.
+    pub fn map_guard_page<P: PhysMem>(&mut self, phys_mem: &mut P,
+            vaddr: VirtAddr) -> Result<(), PageTableError> {
+        // Guard pages are always 4 KiB
+        if (vaddr.0 & (PageType::Page4K as u64 - 1)) != 0 {
+            return Err(PageTableError::InvalidRange);
+        }
+
+        // Never replace an existing mapping with a guard page
+        let mapping = self.translate(phys_mem, vaddr)
+            .ok_or(PageTableError::NonCanonical(vaddr))?;
+        if mapping.page.is_some() {
+            return Err(PageTableError::DuplicateMapping(vaddr));
+        }
+
+        unsafe {
+            self.map_entry(phys_mem, vaddr, PageType::Page4K,
+                           PAGE_GUARD | PAGE_NX).map(|_| ())
+        }
+    }
+
+    /// Map the 4 KiB page at `vaddr` to `paddr` as uncacheable, read-write,
+    /// non-executable memory, for MMIO. If `vaddr` is already mapped to
+    /// `paddr`, only the caching of the existing mapping is changed. A large
+    /// page is first split into 4 KiB pages, such that only the page at
+    /// `vaddr` changes.
+    ///
+    /// The caller is responsible for invalidating the TLB for `vaddr`. The
+    /// other pages of a split large page keep their translation and caching
+    /// attributes, only the page size of their TLB entries is stale.
+    pub fn map_uncacheable<P: PhysMem>(&mut self, phys_mem: &mut P,
+            vaddr: VirtAddr, paddr: u64) -> Result<(), PageTableError> {
+        // Only whole pages can be mapped
+        if (vaddr.0 & 0xfff) != 0 || (paddr & 0xfff) != 0 {
+            return Err(PageTableError::MisalignedAddress {
+                vaddr: vaddr,
+                align: PageType::Page4K as u64,
+            });
+        }
+
+        loop {
+            let mapping = self.translate(phys_mem, vaddr)
+                .ok_or(PageTableError::NonCanonical(vaddr))?;
+
+            // Not mapped yet, create a new uncacheable mapping
+            let page_type = match mapping.size() {
+                Some(page_type) => page_type,
+                None => unsafe {
+                    return self.map_raw(phys_mem, vaddr, PageType::Page4K,
+                        paddr | PAGE_CACHE_UC | PAGE_NX | PAGE_WRITE |
+                        PAGE_PRESENT).map(|_| ());
+                }
+            };
+
+            // Never change the attributes of some other memory
+            let (page, offset) = mapping.page.unwrap();
+            if page.0 + offset != paddr {
+                return Err(PageTableError::DuplicateMapping(vaddr));
+            }
+
+            // Get the final entry of the translation
+            let entry = match page_type {
+                PageType::Page4K => mapping.pte,
+                PageType::Page2M => mapping.pde,
+                PageType::Page1G => mapping.pdpe,
+            }.unwrap();
+
+            unsafe {
+                let entry = &*(phys_mem.translate(entry, size_of::<u64>())
+                    as *const AtomicU64);
+
+                if page_type != PageType::Page4K {
+                    // Split the large page and look up the page again
+                    self.split_large_page(phys_mem, entry, page_type)?;
+                    continue;
+                }
+
+                // Select the UC PAT entry, bit 7 is the PAT bit for 4 KiB
+                // pages
+                entry.fetch_and(!(PAGE_PWT | PAGE_PCD | (1 << 7)),
+                                Ordering::SeqCst);
+                entry.fetch_or(PAGE_CACHE_UC, Ordering::SeqCst);
+            }
+
+            return Ok(());
+        }
+    }
+
+    /// Replace the large page of `page_type` at the page table entry `entry`
+    /// with a table of the next page size down, mapping the same memory with
+    /// the same permissions and caching attributes. On error, the large page
+    /// is left as it was.
+    unsafe fn split_large_page<P: PhysMem>(&mut self, phys_mem: &mut P,
+            entry: &AtomicU64, page_type: PageType)
+            -> Result<(), PageTableError> {
+        /// The PAT bit of a large page entry
+        const PAGE_PAT_LARGE: u64 = 1 << 12;
+
+        /// The PAT bit of a 4 KiB page entry
+        const PAGE_PAT_4K: u64 = 1 << 7;
+
+        let old = entry.load(Ordering::SeqCst);
+
+        // Get the page, and the attributes which are kept for the smaller
+        // pages
+        let (small, page_mask) = match page_type {
+            PageType::Page1G => (PageType::Page2M, PageType::Page1G as u64 - 1),
+            PageType::Page2M => (PageType::Page4K, PageType::Page2M as u64 - 1),
+            PageType::Page4K => unreachable!(),
+        };
+        let paddr = old & 0xffffffffff000 & !page_mask;
+        let attrs = old & !0xffffffffff000 & !(PAGE_SIZE | PAGE_PAT_LARGE);
+
+        // Large pages keep `PAGE_SIZE` and the PAT bit where it was, it moves
+        // to bit 7 for 4 KiB pages
+        let pat = (old & PAGE_PAT_LARGE) != 0;
+        let attrs = attrs | match (small, pat) {
+            (PageType::Page4K, true)  => PAGE_PAT_4K,
+            (PageType::Page4K, false) => 0,
+            (_,                true)  => PAGE_SIZE | PAGE_PAT_LARGE,
+            (_,                false) => PAGE_SIZE,
+        };
+
+        // Fill in a new table with the smaller pages
+        let table = phys_mem.alloc_phys_zeroed(
+            Layout::from_size_align(4096, 4096).unwrap())
+            .ok_or(PageTableError::OutOfMemory)?;
+        let entries = phys_mem.translate(table, 4096) as *mut u64;
+        for ii in 0..512 {
+            core::ptr::write(entries.add(ii),
+                (paddr + ii as u64 * small as u64) | attrs);
+        }
+
+        // Point the entry at the new table, with the permissions of the large
+        // page, such that they're the same for all of the pages under it
+        entry.store(table.0 | (old & (PAGE_NX | PAGE_USER | PAGE_WRITE |
+            PAGE_PRESENT)), Ordering::SeqCst);
+
+        Ok(())
+    }
+
     /// Map a `vaddr` to a raw page table entry `raw`. This will use the page
     /// size specified by `page_type`.
     ///
-    /// If the mapping already exists, or the virtual address is non-canonical
-    /// then this returns `None`. In this case, no modifications were made to
-    /// the page table.
+    /// If a mapping of the same page size already exists, it is replaced and
+    /// the old raw page table entry is returned as `Some`. It is up to the
+    /// caller to invalidate the TLB and handle the old page. If the slot was
+    /// empty, `None` is returned.
+    ///
+    /// On error, no modifications were made to the page table.
     ///
     /// * `vaddr`     - Virtual address to create the mapping at
     /// * `page_type` - The page size to be used for the entry
     /// * `raw`       - The raw page table entry to use
     pub unsafe fn map_raw<P: PhysMem>(
             &mut self, phys_mem: &mut P, vaddr: VirtAddr, page_type: PageType,
-            raw: u64) -> Option<()> {
+            raw: u64) -> Result<Option<u64>, PageTableError> {
+        debug_assert!(vaddr.is_canonical(),
+            "Non-canonical address passed to map_raw: {:#x}", vaddr.0);
+
         // We're mapping a non-present page or we're mapping a large page
         // without the page size bit set, this page will _never_ be valid so
         // just return fail.
         if (raw & PAGE_PRESENT) == 0 ||
                 (page_type != PageType::Page4K && (raw & PAGE_SIZE) == 0) {
-            return None;
+            return Err(PageTableError::InvalidEntry);
         }
 
+        self.map_entry(phys_mem, vaddr, page_type, raw)
+    }
+
+    /// Write the raw page table entry `raw` for `vaddr` with the page size
+    /// `page_type`, creating tables as needed. This behaves the same as
+    /// `map_raw`, but does not require `raw` to be present.
+    unsafe fn map_entry<P: PhysMem>(
+            &mut self, phys_mem: &mut P, vaddr: VirtAddr, page_type: PageType,
+            raw: u64) -> Result<Option<u64>, PageTableError> {
         // Determine the state of the existing mapping
-        let mapping = self.translate(phys_mem, vaddr)?;
+        let mapping = self.translate(phys_mem, vaddr)
+            .ok_or(PageTableError::NonCanonical(vaddr))?;
+
+        // The last entry in the walk is either the page, or the first entry
+        // which was not present. If it's a guard page, never map over it.
+        let last = [mapping.pte, mapping.pde, mapping.pdpe, mapping.pml4e]
+            .iter().find_map(|x| *x).unwrap();
+        let last = core::ptr::read(
+            phys_mem.translate(last, core::mem::size_of::<u64>())
+            as *const u64);
+        if (last & (PAGE_PRESENT | PAGE_GUARD)) == PAGE_GUARD {
+            return Err(PageTableError::GuardPageViolation(vaddr));
+        }
 
         // Page already mapped
-        if mapping.page.is_some() {
-            return None;
+        if let Some(size) = mapping.size() {
+            // We can only replace mappings of the same size
+            if size != page_type {
+                return Err(PageTableError::SizeConflict(vaddr));
+            }
+
+            // Get the final entry of the translation
+            let entry = match page_type {
+                PageType::Page4K => mapping.pte,
+                PageType::Page2M => mapping.pde,
+                PageType::Page1G => mapping.pdpe,
+            }.unwrap();
+
+            // Replace the entry
+            let ptr = phys_mem.translate(entry, core::mem::size_of::<u64>());
+            let old = core::ptr::read(ptr as *const u64);
+            core::ptr::write(ptr as *mut u64, raw);
+
+            return Ok(Some(old));
         }
 
         // Get all of the current mapping states
@@ -492,11 +1207,13 @@ impl PageTable {
         // to insert a large page. This will disallow us from mapping a large
         // page over a table which contains smaller pages.
         if entries.get(depth).map_or(false, |x| x.is_some()) {
-            return None;
+            return Err(PageTableError::SizeConflict(vaddr));
         }
         
         // After this point, we should never return partial success. We should
-        // either panic or return success!
+        // either panic or return success! The only exception is running out
+        // of memory for a table, which leaves the tables created so far in
+        // place. They're empty, thus nothing new is mapped.
 
         // This should never happen. This means the page table doesn't even
         // exist.
@@ -516,7 +1233,8 @@ impl PageTable {
             if entries[ii].is_none() {
                 // Allocate a new empty table
                 let table = phys_mem.alloc_phys_zeroed(
-                    Layout::from_size_align(4096, 4096).unwrap());
+                    Layout::from_size_align(4096, 4096).unwrap())
+                    .ok_or(PageTableError::OutOfMemory)?;
 
                 // Convert the address of the page table entry where we need
                 // to insert the new table
@@ -540,7 +1258,89 @@ impl PageTable {
             core::mem::size_of::<u64>());
         core::ptr::write(ptr as *mut u64, raw);
 
-        Some(())
+        Ok(None)
     }
 }
 
+/// An iterator over the present leaf entries of a page table. This walks the
+/// table depth-first with an explicit stack of tables rather than recursing.
+struct PresentLeaves<'a, P: PhysMem> {
+    /// Physical memory used to access the page tables
+    phys_mem: &'a mut P,
+
+    /// The table currently being walked at each depth
+    tables: [PhysAddr; 4],
+
+    /// The index of the next entry to look at in each table
+    indicies: [u64; 4],
+
+    /// The current depth in the walk, 0 is the PML4 and 4 means the walk is
+    /// complete
+    depth: usize,
+}
+
+impl<'a, P: PhysMem> Iterator for PresentLeaves<'a, P> {
+    type Item = (VirtAddr, u64, PageType);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.depth < 4 {
+            let depth = self.depth;
+            let index = self.indicies[depth];
+
+            // If we're done with this table, go back up to the parent
+            if index == 512 {
+                if depth == 0 {
+                    // Walked the whole table
+                    self.depth = 4;
+                    return None;
+                }
+
+                self.depth -= 1;
+                self.indicies[self.depth] += 1;
+                continue;
+            }
+
+            // Read the entry
+            let ptp = PhysAddr(self.tables[depth].0 +
+                               index * size_of::<u64>() as u64);
+            let ent = unsafe {
+                core::ptr::read(self.phys_mem.translate(ptp,
+                    size_of::<u64>()) as *const u64)
+            };
+
+            // Skip entries which are not present. The page size bit is not
+            // valid for a PML4E, so skip those too as `translate` would.
+            if (ent & PAGE_PRESENT) == 0 ||
+                    (depth == 0 && (ent & PAGE_SIZE) != 0) {
+                self.indicies[depth] += 1;
+                continue;
+            }
+
+            // Check if this is a page mapping rather than a table
+            if depth == 3 || (ent & PAGE_SIZE) != 0 {
+                // Compute the virtual address from the indicies walked
+                let vaddr = self.indicies[..=depth].iter().enumerate()
+                    .fold(0u64, |acc, (ii, &idx)| {
+                        acc | (idx << (39 - ii * 9))
+                    });
+
+                let page_type = match depth {
+                    1 => PageType::Page1G,
+                    2 => PageType::Page2M,
+                    _ => PageType::Page4K,
+                };
+
+                self.indicies[depth] += 1;
+                return Some((VirtAddr(cpu::canonicalize_address(vaddr)),
+                             ent, page_type));
+            }
+
+            // Descend into the table
+            self.depth += 1;
+            self.tables[self.depth]   = PhysAddr(ent & 0xffffffffff000);
+            self.indicies[self.depth] = 0;
+        }
+
+        None
+    }
+}
@@ -0,0 +1,15 @@
+//! Fuzz `PeParser::parse` as well as all the accessors of a parsed PE, as all
+//! of these operate on untrusted data downloaded over the network
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pe_parser::PeParser;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(pe) = PeParser::parse(data) {
+        let _ = pe.entry_point;
+        let _ = pe.sections(|_, _, _, _, _, _| Some(()));
+        let _ = pe.section_by_name(".text");
+        let _ = pe.verify_checksum();
+    }
+});
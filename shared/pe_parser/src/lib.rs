@@ -1,6 +1,9 @@
 #![no_std]
 
+extern crate alloc;
+
 use core::convert::TryInto;
+use alloc::vec::Vec;
 
 const IMAGE_FILE_MACHINE_I386:   u16 = 0x014c;
 const IMAGE_FILE_MACHINE_X86_64: u16 = 0x8664;
@@ -9,6 +12,52 @@ const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
 const IMAGE_SCN_MEM_READ:    u32 = 0x4000_0000;
 const IMAGE_SCN_MEM_WRITE:   u32 = 0x8000_0000;
 
+/// Required alignment of the `ImageBase`
+const PAGE_SIZE: u64 = 4096;
+
+/// Information about a single section in a PE file
+pub struct PeSectionInfo<'a> {
+    /// Virtual address of the section
+    pub vaddr: u64,
+
+    /// Size of the section in memory (in bytes)
+    pub vsize: u64,
+
+    /// Raw initialized bytes of the section from the file
+    pub raw: &'a [u8],
+
+    /// Section is readable
+    pub read: bool,
+
+    /// Section is writable
+    pub write: bool,
+
+    /// Section is executable
+    pub execute: bool,
+}
+
+/// An owned copy of a section in a PE file, see `map_sections_to_vec`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeSectionDesc {
+    /// Virtual address of the section
+    pub vaddr: u64,
+
+    /// Size of the section in memory (in bytes)
+    pub vsize: u64,
+
+    /// Raw initialized bytes of the section from the file
+    pub raw: Vec<u8>,
+
+    /// Section is readable
+    pub read: bool,
+
+    /// Section is writable
+    pub write: bool,
+
+    /// Section is executable
+    pub execute: bool,
+}
+
 /// A validated PE file that has had some basic information parsed out of it.
 /// You can use functions on this structure to extract things like sections.
 pub struct PeParser<'a> {
@@ -24,6 +73,18 @@ pub struct PeParser<'a> {
     /// Base of the image
     image_base: u64,
 
+    /// Size of the image when loaded in memory, `SizeOfImage`
+    image_size: u64,
+
+    /// Offset into the raw PE file where the `CheckSum` field is
+    checksum_off: usize,
+
+    /// Offset into the raw PE file where the data directories are
+    data_dirs_off: usize,
+
+    /// Number of data directories present in the optional header
+    num_data_dirs: usize,
+
     /// Virtual address of the entry point
     pub entry_point: u64,
 }
@@ -82,6 +143,22 @@ impl<'a> PeParser<'a> {
         } else {
             unreachable!();
         };
+
+        // The image must be loadable at its base
+        if image_base & (PAGE_SIZE - 1) != 0 {
+            return None;
+        }
+
+        // Get the section and file alignments, which must be powers of two
+        let section_align = u32::from_le_bytes(
+            bytes.get(pe_offset + 0x38..pe_offset + 0x3c)?
+            .try_into().ok()?);
+        let file_align = u32::from_le_bytes(
+            bytes.get(pe_offset + 0x3c..pe_offset + 0x40)?
+            .try_into().ok()?);
+        if !section_align.is_power_of_two() || !file_align.is_power_of_two() {
+            return None;
+        }
         
         // Get the entry point for the image
         let entry_point: u64 = u32::from_le_bytes(
@@ -89,6 +166,34 @@ impl<'a> PeParser<'a> {
             .try_into().ok()?) as u64;
         let entry_point = image_base.checked_add(entry_point)?;
 
+        // Get the size of the loaded image
+        let image_size: u64 = u32::from_le_bytes(
+            bytes.get(pe_offset + 0x50..pe_offset + 0x54)?
+            .try_into().ok()?) as u64;
+
+        // Make sure the checksum is in bounds
+        let checksum_off = pe_offset + 0x58;
+        bytes.get(checksum_off..checksum_off + 4)?;
+
+        // Find the data directories, which sit at the end of the optional
+        // header at a different offset for PE32 and PE32+
+        let (num_dirs_off, dirs_off) = if machine == IMAGE_FILE_MACHINE_I386 {
+            (0x5c, 0x60)
+        } else {
+            (0x6c, 0x70)
+        };
+        let num_data_dirs = if opt_header_size >= dirs_off {
+            // Get the number of data directories, capped to what actually
+            // fits in the optional header
+            let num: usize = u32::from_le_bytes(
+                bytes.get(pe_offset + 0x18 + num_dirs_off..
+                          pe_offset + 0x18 + num_dirs_off + 4)?
+                .try_into().ok()?).try_into().ok()?;
+            core::cmp::min(num, (opt_header_size - dirs_off) / 8)
+        } else {
+            0
+        };
+
         // Compute the size of all headers, including sections and make sure
         // everything is in bounds
         let header_size = pe_offset.checked_add(0x18)?
@@ -98,55 +203,429 @@ impl<'a> PeParser<'a> {
             return None;
         }
 
-        Some(PeParser {
+        let pe = PeParser {
             bytes,
             image_base,
+            image_size,
+            checksum_off,
+            data_dirs_off: pe_offset + 0x18 + dirs_off,
+            num_data_dirs,
             num_sections,
             entry_point,
             section_off: pe_offset + 0x18 + opt_header_size,
-        })
+        };
+
+        // Make sure we'd actually be able to execute the entry point
+        if !pe.validate_entry_point() {
+            return None;
+        }
+
+        Some(pe)
     }
 
-    /// Invoke a closure with the format
-    /// (virtual addr, virtual size, raw initialize bytes,
-    ///  read, write, execute) for each section in the PE file
-    pub fn sections<F>(&self, mut func: F) -> Option<()>
-            where F: FnMut(u64, u32, &[u8], bool, bool, bool) -> Option<()> {
+    /// Check that the entry point falls within an executable section
+    pub fn validate_entry_point(&self) -> bool {
         let bytes = self.bytes;
 
-        for section in 0..self.num_sections {
+        (0..self.num_sections).any(|section| {
             let off = self.section_off + section * 0x28;
 
-            // Get the virtual and raw sizes and offsets
+            // Get the virtual size, address, and characteristics of the
+            // section. The raw data is not needed, so this doesn't fail for
+            // sections with raw data out of bounds like `section` does.
             let virt_size = u32::from_le_bytes(
-                bytes[off + 0x8..off + 0xc].try_into().ok()?);
+                bytes[off + 0x8..off + 0xc].try_into().unwrap()) as u64;
             let virt_addr = u32::from_le_bytes(
-                bytes[off + 0xc..off + 0x10].try_into().ok()?);
-            let raw_size = u32::from_le_bytes(
-                bytes[off + 0x10..off + 0x14].try_into().ok()?);
-            let raw_off: usize = u32::from_le_bytes(
-                bytes[off + 0x14..off + 0x18].try_into().ok()?)
-                .try_into().ok()?;
-
-            // Get the section characteristics
+                bytes[off + 0xc..off + 0x10].try_into().unwrap()) as u64;
             let characteristics = u32::from_le_bytes(
-                bytes[off + 0x24..off + 0x28].try_into().ok()?);
+                bytes[off + 0x24..off + 0x28].try_into().unwrap());
+
+            let start = match self.image_base.checked_add(virt_addr) {
+                Some(start) => start,
+                None        => return false,
+            };
+            (characteristics & IMAGE_SCN_MEM_EXECUTE) != 0 &&
+                self.entry_point >= start &&
+                self.entry_point - start < virt_size
+        })
+    }
+
+    /// Validate a PE file the same as `parse`, additionally rejecting any PE
+    /// which claims to be larger than `max_image_size` bytes when loaded
+    pub fn parse_with_max_size(bytes: &'a [u8], max_image_size: usize)
+            -> Option<Self> {
+        let pe = Self::parse(bytes)?;
+
+        // Make sure the `SizeOfImage` is within our limit
+        if pe.image_size > max_image_size as u64 {
+            return None;
+        }
+
+        Some(pe)
+    }
+
+    /// Verify the `CheckSum` in the optional header matches the contents of
+    /// the file. Images with a zero `CheckSum` are considered valid, as most
+    /// linkers do not compute one by default.
+    pub fn verify_checksum(&self) -> bool {
+        let bytes = self.bytes;
+
+        // Get the expected checksum
+        let off = self.checksum_off;
+        let expected = u32::from_le_bytes(
+            bytes[off..off + 4].try_into().unwrap());
+        if expected == 0 {
+            return true;
+        }
+
+        // Sum all of the 16-bit words in the file with the carries folded
+        // back in, treating the checksum field itself as zero
+        let mut sum = 0u32;
+        for (ii, word) in bytes.chunks(2).enumerate() {
+            let off = ii * 2;
+            if off >= self.checksum_off && off < self.checksum_off + 4 {
+                continue;
+            }
+
+            // An odd-sized file has the last byte padded with a zero
+            let word = word[0] as u32 |
+                (*word.get(1).unwrap_or(&0) as u32) << 8;
+
+            sum += word;
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+
+        // The checksum is the fully folded sum plus the size of the file
+        sum = (sum & 0xffff) + (sum >> 16);
+        (sum as u64 + bytes.len() as u64) == expected as u64
+    }
+
+    /// Get the base virtual address of the image
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    /// Get the (virtual address, size) of the export directory, if the PE has
+    /// one
+    pub fn export_directory(&self) -> Option<(u64, u32)> {
+        // The export table is the first data directory
+        if self.num_data_dirs < 1 {
+            return None;
+        }
+
+        let off = self.data_dirs_off;
+        let rva = u32::from_le_bytes(
+            self.bytes[off..off + 4].try_into().ok()?);
+        let size = u32::from_le_bytes(
+            self.bytes[off + 4..off + 8].try_into().ok()?);
+
+        // An empty directory means there are no exports
+        if rva == 0 || size == 0 {
+            return None;
+        }
+
+        Some((self.image_base.checked_add(rva as u64)?, size))
+    }
+
+    /// Get the raw name and information for the section at index `section`
+    fn section(&self, section: usize) -> Option<(&'a [u8], PeSectionInfo<'a>)> {
+        let bytes = self.bytes;
+        let off = self.section_off + section * 0x28;
+
+        // Get the name of the section, which is padded with nul bytes
+        let name = &bytes[off..off + 0x8];
+        let name = &name[..name.iter().position(|&x| x == 0)
+            .unwrap_or(name.len())];
+
+        // Get the virtual and raw sizes and offsets
+        let virt_size = u32::from_le_bytes(
+            bytes[off + 0x8..off + 0xc].try_into().ok()?);
+        let virt_addr = u32::from_le_bytes(
+            bytes[off + 0xc..off + 0x10].try_into().ok()?);
+        let raw_size = u32::from_le_bytes(
+            bytes[off + 0x10..off + 0x14].try_into().ok()?);
+        let raw_off: usize = u32::from_le_bytes(
+            bytes[off + 0x14..off + 0x18].try_into().ok()?)
+            .try_into().ok()?;
+
+        // Get the section characteristics
+        let characteristics = u32::from_le_bytes(
+            bytes[off + 0x24..off + 0x28].try_into().ok()?);
+
+        // Truncate the raw size if it exceeds the section size
+        let raw_size: usize = core::cmp::min(raw_size, virt_size)
+            .try_into().ok()?;
+
+        Some((name, PeSectionInfo {
+            vaddr:   self.image_base.checked_add(virt_addr as u64)?,
+            vsize:   virt_size as u64,
+            raw:     bytes.get(raw_off..raw_off.checked_add(raw_size)?)?,
+            read:    (characteristics & IMAGE_SCN_MEM_READ)    != 0,
+            write:   (characteristics & IMAGE_SCN_MEM_WRITE)   != 0,
+            execute: (characteristics & IMAGE_SCN_MEM_EXECUTE) != 0,
+        }))
+    }
+
+    /// Get the first section in the PE file named `name`
+    pub fn section_by_name(&self, name: &str) -> Option<PeSectionInfo<'a>> {
+        for section in 0..self.num_sections {
+            let (sname, info) = self.section(section)?;
+            if sname == name.as_bytes() {
+                return Some(info);
+            }
+        }
 
-            // Truncate the raw size if it exceeds the section size
-            let raw_size: usize = core::cmp::min(raw_size, virt_size)
-                .try_into().ok()?;
+        None
+    }
+
+    /// Invoke a closure with the format
+    /// (virtual addr, virtual size, raw initialize bytes,
+    ///  read, write, execute) for each section in the PE file
+    pub fn sections<F>(&self, mut func: F) -> Option<()>
+            where F: FnMut(u64, u32, &[u8], bool, bool, bool) -> Option<()> {
+        for section in 0..self.num_sections {
+            let (_, info) = self.section(section)?;
 
             // Invoke the closure
-            func(
-                self.image_base.checked_add(virt_addr as u64)?,
-                virt_size,
-                bytes.get(raw_off..raw_off.checked_add(raw_size)?)?,
-                (characteristics & IMAGE_SCN_MEM_READ)    != 0,
-                (characteristics & IMAGE_SCN_MEM_WRITE)   != 0,
-                (characteristics & IMAGE_SCN_MEM_EXECUTE) != 0)?;
+            func(info.vaddr, info.vsize as u32, info.raw,
+                 info.read, info.write, info.execute)?;
         }
 
         Some(())
     }
+
+    /// Get an owned copy of every section in the PE file, such that the
+    /// sections can be processed multiple times. This copies the raw bytes of
+    /// each section, use `sections` to avoid the copies.
+    pub fn map_sections_to_vec(&self) -> Option<Vec<PeSectionDesc>> {
+        let mut sections = Vec::with_capacity(self.num_sections);
+
+        self.sections(|vaddr, vsize, raw, read, write, execute| {
+            sections.push(PeSectionDesc {
+                vaddr,
+                vsize: vsize as u64,
+                raw:   raw.to_vec(),
+                read,
+                write,
+                execute,
+            });
+            Some(())
+        })?;
+
+        Some(sections)
+    }
 }
 
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+    use crate::PeParser;
+
+    /// Offset of the PE header in the test images, after a 64-byte DOS header
+    /// and a 32-byte DOS stub
+    const PE: usize = 0x60;
+
+    /// Offset of the first section header in the test images
+    const SECTIONS: usize = PE + 0x18 + 0xf0;
+
+    /// Image base of the test images
+    const BASE: u64 = 0x1337_0000_0000;
+
+    /// Write `val` as little-endian at `off` in `bytes`
+    fn put(bytes: &mut [u8], off: usize, val: &[u8]) {
+        bytes[off..off + val.len()].copy_from_slice(val);
+    }
+
+    /// Build a minimal valid PE64 with a single `.text` section
+    fn minimal_pe64() -> Vec<u8> {
+        let mut pe = std::vec![0u8; 0x400];
+
+        // DOS header
+        put(&mut pe, 0, b"MZ");
+        put(&mut pe, 0x3c, &(PE as u32).to_le_bytes());
+
+        // COFF header, x86_64 with one section and a full PE32+ optional
+        // header
+        put(&mut pe, PE, b"PE\0\0");
+        put(&mut pe, PE + 0x04, &0x8664u16.to_le_bytes());
+        put(&mut pe, PE + 0x06, &1u16.to_le_bytes());
+        put(&mut pe, PE + 0x14, &0xf0u16.to_le_bytes());
+
+        // Optional header
+        put(&mut pe, PE + 0x18, &0x20bu16.to_le_bytes());
+        put(&mut pe, PE + 0x28, &0x1000u32.to_le_bytes());
+        put(&mut pe, PE + 0x30, &BASE.to_le_bytes());
+        put(&mut pe, PE + 0x38, &0x1000u32.to_le_bytes());
+        put(&mut pe, PE + 0x3c, &0x200u32.to_le_bytes());
+        put(&mut pe, PE + 0x50, &0x2000u32.to_le_bytes());
+        put(&mut pe, PE + 0x84, &16u32.to_le_bytes());
+
+        // `.text` section, RX, with its raw data at 0x200
+        put(&mut pe, SECTIONS, b".text");
+        put(&mut pe, SECTIONS + 0x08, &0x100u32.to_le_bytes());
+        put(&mut pe, SECTIONS + 0x0c, &0x1000u32.to_le_bytes());
+        put(&mut pe, SECTIONS + 0x10, &0x200u32.to_le_bytes());
+        put(&mut pe, SECTIONS + 0x14, &0x200u32.to_le_bytes());
+        put(&mut pe, SECTIONS + 0x24, &0x6000_0020u32.to_le_bytes());
+
+        pe
+    }
+
+    #[test]
+    fn test_valid_pe64() {
+        let pe = minimal_pe64();
+        let parsed = PeParser::parse(&pe).unwrap();
+        assert!(parsed.entry_point == BASE + 0x1000);
+        assert!(parsed.verify_checksum());
+
+        // Make sure the one section is reported correctly
+        let mut sections = 0;
+        parsed.sections(|vaddr, vsize, raw, read, write, execute| {
+            assert!(vaddr == BASE + 0x1000 && vsize == 0x100);
+            assert!(raw.len() == 0x100);
+            assert!(read && !write && execute);
+            sections += 1;
+            Some(())
+        }).unwrap();
+        assert!(sections == 1);
+
+        let text = parsed.section_by_name(".text").unwrap();
+        assert!(text.vaddr == BASE + 0x1000 && text.vsize == 0x100);
+        assert!(parsed.section_by_name(".data").is_none());
+    }
+
+    #[test]
+    fn test_map_sections_to_vec() {
+        let pe = minimal_pe64();
+        let parsed = PeParser::parse(&pe).unwrap();
+
+        let sections = parsed.map_sections_to_vec().unwrap();
+        assert!(sections.len() == 1);
+        assert!(sections[0].vaddr == BASE + 0x1000);
+        assert!(sections[0].vsize == 0x100);
+        assert!(sections[0].raw[..] == pe[0x200..0x300]);
+        assert!(sections[0].read && !sections[0].write && sections[0].execute);
+    }
+
+    #[test]
+    fn test_invalid_lfanew() {
+        let mut pe = minimal_pe64();
+
+        // Past the end of the file
+        put(&mut pe, 0x3c, &0x1000u32.to_le_bytes());
+        assert!(PeParser::parse(&pe).is_none());
+
+        // Overflowing when adding the header sizes
+        put(&mut pe, 0x3c, &0xffff_fff0u32.to_le_bytes());
+        assert!(PeParser::parse(&pe).is_none());
+    }
+
+    #[test]
+    fn test_section_overlapping_header() {
+        let mut pe = minimal_pe64();
+
+        // Raw data of a section may overlap the headers, it's just bytes
+        put(&mut pe, SECTIONS + 0x14, &0u32.to_le_bytes());
+        let parsed = PeParser::parse(&pe).unwrap();
+        let text = parsed.section_by_name(".text").unwrap();
+        assert!(text.raw == &pe[..0x100]);
+
+        // But raw data past the end of the file is rejected
+        put(&mut pe, SECTIONS + 0x14, &0x3800u32.to_le_bytes());
+        let parsed = PeParser::parse(&pe).unwrap();
+        assert!(parsed.sections(|_, _, _, _, _, _| Some(())).is_none());
+        assert!(parsed.section_by_name(".text").is_none());
+    }
+
+    #[test]
+    fn test_huge_size_of_image() {
+        let mut pe = minimal_pe64();
+        put(&mut pe, PE + 0x50, &0xffff_ffffu32.to_le_bytes());
+
+        assert!(PeParser::parse(&pe).is_some());
+        assert!(PeParser::parse_with_max_size(&pe, 256 * 1024 * 1024)
+            .is_none());
+    }
+
+    #[test]
+    fn test_empty_section_table() {
+        let mut pe = minimal_pe64();
+        put(&mut pe, PE + 0x06, &0u16.to_le_bytes());
+
+        // With no sections the entry point can't be in an executable one
+        assert!(PeParser::parse(&pe).is_none());
+    }
+
+    #[test]
+    fn test_bad_entry_point() {
+        let mut pe = minimal_pe64();
+
+        // Entry point past the end of `.text`
+        put(&mut pe, PE + 0x28, &0x1100u32.to_le_bytes());
+        assert!(PeParser::parse(&pe).is_none());
+
+        // Entry point in `.text`, but `.text` isn't executable
+        put(&mut pe, PE + 0x28, &0x10ffu32.to_le_bytes());
+        assert!(PeParser::parse(&pe).is_some());
+        put(&mut pe, SECTIONS + 0x24, &0x4000_0020u32.to_le_bytes());
+        assert!(PeParser::parse(&pe).is_none());
+    }
+
+    #[test]
+    fn test_bad_alignment() {
+        // `ImageBase` not page aligned
+        let mut pe = minimal_pe64();
+        put(&mut pe, PE + 0x30, &(BASE + 0x800).to_le_bytes());
+        assert!(PeParser::parse(&pe).is_none());
+
+        // `SectionAlignment` not a power of two
+        let mut pe = minimal_pe64();
+        put(&mut pe, PE + 0x38, &0x1800u32.to_le_bytes());
+        assert!(PeParser::parse(&pe).is_none());
+
+        // `FileAlignment` of zero
+        let mut pe = minimal_pe64();
+        put(&mut pe, PE + 0x3c, &0u32.to_le_bytes());
+        assert!(PeParser::parse(&pe).is_none());
+    }
+
+    #[test]
+    fn test_good_checksum() {
+        // `cli; hlt; jmp $-1` at the entry point, such that the file is not
+        // all zeros and headers
+        let mut pe = minimal_pe64();
+        put(&mut pe, 0x200, &[0xfa, 0xf4, 0xeb, 0xfd]);
+
+        // Checksum as computed by `pefile`'s `generate_checksum`, which
+        // implements the algorithm of `CheckSumMappedFile` with 32-bit words
+        put(&mut pe, PE + 0x58, &0xd7b4u32.to_le_bytes());
+        assert!(PeParser::parse(&pe).unwrap().verify_checksum());
+
+        // Any change to the contents must be caught
+        pe[0x201] ^= 1;
+        assert!(!PeParser::parse(&pe).unwrap().verify_checksum());
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut pe = minimal_pe64();
+        put(&mut pe, PE + 0x58, &0x1234u32.to_le_bytes());
+
+        assert!(!PeParser::parse(&pe).unwrap().verify_checksum());
+    }
+
+    #[test]
+    fn test_truncated() {
+        // No truncation of a valid image may cause a panic
+        let pe = minimal_pe64();
+        for len in 0..pe.len() {
+            if let Some(parsed) = PeParser::parse(&pe[..len]) {
+                let _ = parsed.sections(|_, _, _, _, _, _| Some(()));
+                let _ = parsed.section_by_name(".text");
+                let _ = parsed.verify_checksum();
+            }
+        }
+    }
+}
@@ -172,6 +172,11 @@ impl RangeSet {
         }
     }
 
+    /// Returns true if `addr` is contained in any range in the set
+    pub fn contains(&self, addr: u64) -> bool {
+        self.entries().iter().any(|x| addr >= x.start && addr <= x.end)
+    }
+
     /// Compute the size of the range covered by this rangeset
     pub fn sum(&self) -> Option<u64> {
         self.entries().iter().try_fold(0u64, |acc, x| {
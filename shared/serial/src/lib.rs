@@ -2,12 +2,23 @@
 
 #![no_std]
 
+/// Standard I/O port base addresses of COM1-COM4
+const COM_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+
+/// Number of bytes written when probing the depth of a UART's FIFO. This is
+/// larger than any FIFO we expect to find.
+const FIFO_PROBE_SIZE: usize = 512;
+
 /// A collection of 4 8250A serial ports, as seen on IBM PC systems. These are
 /// the 4 serial ports which are identified by the BIOS, and thus it is limited
 /// to just COM1-COM4.
 #[repr(C)]
 pub struct SerialPort {
     devices: [Option<u16>; 4],
+
+    /// Number of bytes which can be written to each device at once when its
+    /// transmit buffer is empty
+    fifo_sizes: [u16; 4],
 }
 
 impl SerialPort {
@@ -18,11 +29,13 @@ impl SerialPort {
     pub unsafe fn new() -> Self {
         // Create a new serial port driver
         let mut ret = SerialPort {
-            devices: [None; 4]
+            devices:    [None; 4],
+            fifo_sizes: [1; 4],
         };
 
         // Go through each possible COM port
-        for (com_id, device) in ret.devices.iter_mut().enumerate() {
+        for (com_id, (device, fifo_size)) in ret.devices.iter_mut()
+                .zip(ret.fifo_sizes.iter_mut()).enumerate() {
             // Get the COM port I/O address from the BIOS data area (BDA)
             let port = *(0x400 as *const u16).offset(com_id as isize);
 
@@ -35,20 +48,136 @@ impl SerialPort {
             }
 
             // Initialize the serial port to a known state
-            cpu::out8(port + 1, 0x00); // Disable all interrupts
-            cpu::out8(port + 3, 0x80); // Enable DLAB
-            cpu::out8(port + 0, 0x01); // Low byte divisor (115200 baud)
-            cpu::out8(port + 1, 0x00); // High byte divisor
-            cpu::out8(port + 3, 0x03); // 8 bits, 1 stop bit, no parity
-            cpu::out8(port + 4, 0x03); // RTS/DSR set
+            Self::init_port(port);
 
             // Save that we found and initialized a serial port
-            *device = Some(port);
+            *device    = Some(port);
+            *fifo_size = Self::probe_fifo_size(port);
         }
 
         ret
     }
 
+    /// Find the first of COM1-COM4 at their standard I/O port addresses which
+    /// passes a loopback test, and create a serial driver for just that port.
+    /// This doesn't rely on the BIOS data area, which may not list the port
+    /// used as the debug console. This should only ever be called once, hence,
+    /// it is marked unsafe.
+    pub unsafe fn detect_first() -> Option<Self> {
+        for &port in COM_PORTS.iter() {
+            Self::init_port(port);
+            if !Self::loopback_port(port) {
+                continue;
+            }
+
+            let mut ret = SerialPort {
+                devices:    [None; 4],
+                fifo_sizes: [1; 4],
+            };
+            ret.devices[0]    = Some(port);
+            ret.fifo_sizes[0] = Self::probe_fifo_size(port);
+            return Some(ret);
+        }
+
+        None
+    }
+
+    /// Initialize the serial port at `port` to 115200n1
+    unsafe fn init_port(port: u16) {
+        cpu::out8(port + 1, 0x00); // Disable all interrupts
+        cpu::out8(port + 3, 0x80); // Enable DLAB
+        cpu::out8(port + 0, 0x01); // Low byte divisor (115200 baud)
+        cpu::out8(port + 1, 0x00); // High byte divisor
+        cpu::out8(port + 3, 0x03); // 8 bits, 1 stop bit, no parity
+        cpu::out8(port + 4, 0x03); // RTS/DSR set
+    }
+
+    /// Send a byte through the serial port at `port` in loopback mode.
+    /// Returns `true` if the byte was echoed back.
+    unsafe fn loopback_port(port: u16) -> bool {
+        // Enable loopback mode
+        cpu::out8(port + 4, 0x13);
+
+        // Drain anything already received, bounded as a missing port reads
+        // as all ones
+        for _ in 0..64 {
+            if (cpu::in8(port + 5) & 0x01) == 0 {
+                break;
+            }
+            cpu::in8(port);
+        }
+
+        // Wait for the output buffer to be ready and send the byte
+        while (cpu::in8(port + 5) & 0x20) == 0 { cpu::pause(); }
+        cpu::out8(port, 0xae);
+
+        // Wait a bounded amount of time for the byte to come back
+        let mut received = None;
+        for _ in 0..100_000 {
+            if (cpu::in8(port + 5) & 0x01) != 0 {
+                received = Some(cpu::in8(port));
+                break;
+            }
+        }
+
+        // Restore normal operation
+        cpu::out8(port + 4, 0x03);
+
+        received == Some(0xae)
+    }
+
+    /// Enable the FIFOs of the UART at `port` and determine how many bytes
+    /// can be written to it at once, returning 1 if it has no FIFO
+    unsafe fn probe_fifo_size(port: u16) -> u16 {
+        // Enable and clear the FIFOs. The 64-byte FIFO enable of the 16750
+        // can only be written with DLAB set.
+        cpu::out8(port + 3, 0x83);
+        cpu::out8(port + 2, 0xe7);
+        cpu::out8(port + 3, 0x03);
+
+        // The IIR reports if the FIFOs are enabled, if they're not this is an
+        // 8250 or 16450 without working FIFOs
+        if (cpu::in8(port + 2) & 0xc0) != 0xc0 {
+            return 1;
+        }
+
+        // There is no standard way to get the depth of the FIFO, so in
+        // loopback mode burst more bytes than any FIFO can hold and count
+        // how many of them come back. Anything beyond the FIFO is dropped.
+        cpu::out8(port + 4, 0x13);
+        while (cpu::in8(port + 5) & 0x20) == 0 { cpu::pause(); }
+        for _ in 0..FIFO_PROBE_SIZE {
+            cpu::out8(port, 0);
+        }
+
+        // Receive until no byte has arrived for a bounded amount of time
+        let mut received = 0;
+        let mut idle     = 0;
+        while idle < 100_000 {
+            if (cpu::in8(port + 5) & 0x01) != 0 {
+                cpu::in8(port);
+                received += 1;
+                idle      = 0;
+            } else {
+                idle += 1;
+            }
+        }
+
+        // Restore normal operation and clear the FIFOs of any overrun
+        cpu::out8(port + 4, 0x03);
+        cpu::out8(port + 2, 0xe7);
+        cpu::in8(port + 5);
+
+        // The shift registers can hold bytes beyond the FIFO, FIFOs are always
+        // a power of two in size
+        let mut size = 1;
+        while size * 2 <= received && size * 2 < FIFO_PROBE_SIZE {
+            size *= 2;
+        }
+
+        size as u16
+    }
+
     /// Write a byte to a COM port
     pub fn write_byte(&mut self, port: usize, byte: u8) {
         // Write a CR prior to all LFs
@@ -58,7 +187,7 @@ impl SerialPort {
         if let Some(&Some(port)) = self.devices.get(port) {
             unsafe {
                 // Wait for the output buffer to be ready
-                while (cpu::in8(port + 5) & 0x20) == 0 {}
+                while (cpu::in8(port + 5) & 0x20) == 0 { cpu::pause(); }
 
                 // Write the byte!
                 cpu::out8(port, byte);
@@ -66,15 +195,56 @@ impl SerialPort {
         }
     }
 
-    /// Write bytes to all known serial devices
+    /// Test all present serial devices by sending a byte through each of them
+    /// in loopback mode. Returns `true` if every device echoed the byte back.
+    pub fn loopback_test(&mut self) -> bool {
+        let mut passed = true;
+
+        for &port in self.devices.iter().flatten() {
+            passed &= unsafe { Self::loopback_port(port) };
+        }
+
+        passed
+    }
+
+    /// Write bytes to all known serial devices, see `write_bytes_fifo`
     pub fn write(&mut self, bytes: &[u8]) {
-        // Go through each byte
-        for &byte in bytes {
-            // Broadcast the byte to all present devices
-            for com_id in 0..self.devices.len() {
-                self.write_byte(com_id, byte);
+        self.write_bytes_fifo(bytes);
+    }
+
+    /// Write bytes to all known serial devices. Each time a device's transmit
+    /// buffer is empty, a burst of up to the size of its FIFO is written,
+    /// rather than waiting for each byte to go out.
+    pub fn write_bytes_fifo(&mut self, bytes: &[u8]) {
+        for (&device, &fifo_size) in self.devices.iter()
+                .zip(self.fifo_sizes.iter()) {
+            // Skip ports which are not present
+            let port = if let Some(port) = device { port } else { continue };
+
+            // Write a CR prior to all LFs
+            let mut expanded = bytes.iter().flat_map(|&byte| {
+                let cr = if byte == b'\n' { Some(b'\r') } else { None };
+                cr.into_iter().chain(core::iter::once(byte))
+            }).peekable();
+
+            while expanded.peek().is_some() {
+                unsafe {
+                    // Wait for the transmit FIFO to be empty
+                    while (cpu::in8(port + 5) & 0x20) == 0 { cpu::pause(); }
+
+                    // Fill it back up
+                    for byte in expanded.by_ref().take(fifo_size as usize) {
+                        cpu::out8(port, byte);
+                    }
+                }
             }
         }
     }
 }
 
+impl core::fmt::Write for SerialPort {
+    fn write_str(&mut self, st: &str) -> core::fmt::Result {
+        self.write(st.as_bytes());
+        Ok(())
+    }
+}
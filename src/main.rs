@@ -102,6 +102,9 @@ fn check_install(command: &str, args: &[&str], expected: &[&str]) -> Option<()>
     }
 }
 
+// Borrowed argument arrays are kept so this builds on older nightlies which
+// lack by-value array iterators
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = std::env::args().collect();
 